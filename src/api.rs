@@ -1,47 +1,347 @@
-use crate::database::{Client, MailRecord};
+use crate::assets::Asset;
+use crate::auth::{inbox_token, verify_inbox_token, verify_secret};
+use crate::database::{BanKind, Client, MailFilter, MailRecord, PageStart};
+use crate::events::MailEvents;
+use crate::message::DecodedMail;
+use crate::openapi;
+use crate::ratelimit::{Quota, RateLimiter};
+use crate::retention::{self, parse_duration, RetentionPolicy};
+use crate::shutdown::ShutdownSignal;
 use anyhow::Result;
-use serde::Serialize;
-use std::{
-    collections::HashMap,
-    sync::atomic::{AtomicUsize, Ordering},
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use http_body_util::{
+    combinators::UnsyncBoxBody, BodyExt, Full, LengthLimitError, Limited, StreamBody,
 };
+use hyper::{
+    body::{Bytes, Frame, Incoming},
+    header::{
+        HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+        ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_HEADERS, ALLOW, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, ETAG,
+        IF_NONE_MATCH, ORIGIN, RETRY_AFTER, VARY, WWW_AUTHENTICATE,
+    },
+    server::conn::http1,
+    service::service_fn,
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::HashMap, convert::Infallible, net::IpAddr, rc::Rc};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    time::{timeout, Duration},
+    net::TcpListener,
+    sync::{broadcast, broadcast::error::RecvError, mpsc, watch},
+    time::{timeout, timeout_at, Duration, Instant},
 };
+use utoipa::ToSchema;
 
-const MAX_SERVED_REQUESTS: usize = 100;
-const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
-const PAGE_SIZE: usize = 10;
-static SERVED_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+pub const DEFAULT_IP_QUOTA: Quota = Quota::per_minute(60);
+pub const DEFAULT_INBOX_QUOTA: Quota = Quota::per_minute(120);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_PAGE_SIZE: u32 = 10;
+pub const DEFAULT_MAX_PAGE_SIZE: u32 = 100;
+/// Long-polling requests are capped below the request timeout,
+/// so that they finish with an empty response rather than a 504
+pub const DEFAULT_MAX_WAIT: Duration = Duration::from_secs(25);
+/// Interval of comment lines sent on idle event streams,
+/// which keeps proxies from closing them and detects disconnected clients
+const STREAM_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+/// Methods accepted by the API, as listed in `Allow` and CORS preflight responses
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, PATCH, DELETE, OPTIONS";
+/// Largest request body accepted by the API
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// How long browsers may cache the result of a CORS preflight request, in seconds
+const CORS_MAX_AGE: u32 = 600;
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
 pub struct InboxMessageSummary {
-    pub id: i64,
+    pub id: String,
     pub date: String,
     pub recipients: Vec<String>,
     pub sender: String,
     pub subject: String,
+    pub seen: bool,
+    pub flagged: bool,
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+impl From<&MailRecord> for InboxMessageSummary {
+    fn from(record: &MailRecord) -> Self {
+        Self {
+            id: record.public_id(),
+            date: record.date.clone(),
+            recipients: split_recipients(&record.recipients),
+            sender: record.sender.clone(),
+            subject: record.subject.clone(),
+            seen: record.seen,
+            flagged: record.flagged,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
 pub struct InboxMessage {
-    pub id: i64,
+    pub id: String,
     pub date: String,
     pub recipients: Vec<String>,
     pub sender: String,
     pub subject: String,
+    /// Plain text of the message, decoded from its MIME parts
     pub body: String,
+    /// The message as received, headers included
+    pub source: String,
+    pub seen: bool,
+    pub flagged: bool,
 }
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
 pub struct InboxListResponse {
     pub mail: Vec<InboxMessageSummary>,
     pub has_more_pages: bool,
+    /// Opaque value of the `cursor` parameter which fetches the next page, if there is one
+    pub next_cursor: Option<String>,
+}
+
+/// Body of `PATCH /inbox/<id>`, where missing fields are left unchanged
+#[derive(Debug, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MessageFlagsUpdate {
+    pub seen: Option<bool>,
+    pub flagged: Option<bool>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct DeleteResponse {
+    pub deleted: u64,
 }
 
-pub fn spawn(port: u16) {
+/// Body of `POST /inbox/<inbox>/ttl`, such as `{"ttl": "2w"}`.
+/// A null `ttl` gives the inbox the default lifetime again.
+#[derive(Debug, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct InboxTtlUpdate {
+    /// Duration like `30m`, `12h`, `7d` or `2w`
+    pub ttl: Option<String>,
+}
+
+/// Response to `POST /inbox/<inbox>/ttl`
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct InboxTtlResponse {
+    pub inbox: String,
+    /// Seconds for which mail for the inbox is kept, or null for the default
+    pub ttl_seconds: Option<u64>,
+}
+
+/// Body of every error response
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+/// Response to `POST /inbox/claim`, carrying the bearer token of the inbox
+#[derive(Debug, Serialize, PartialEq, Eq, ToSchema)]
+pub struct ClaimResponse {
+    pub inbox: String,
+    pub token: String,
+}
+
+/// An inbox and the number of messages addressed to it, as listed by `GET /admin/inboxes`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct InboxCount {
+    pub inbox: String,
+    pub messages: u64,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct InboxCountsResponse {
+    pub inboxes: Vec<InboxCount>,
+    pub has_more_pages: bool,
+    pub next_cursor: Option<String>,
+}
+
+/// Response to `GET /admin/storage`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct StorageResponse {
+    pub messages: u64,
+    /// Messages in the trash, which are included in `messages`
+    pub trashed: u64,
+    pub mail_bytes: u64,
+    pub database_bytes: Option<u64>,
+}
+
+/// Body of `POST /admin/bans`, which must set exactly one of the fields
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BanRequest {
+    pub ip: Option<String>,
+    pub domain: Option<String>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct BanSummary {
+    pub kind: String,
+    pub value: String,
+    pub created: String,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct BansResponse {
+    pub bans: Vec<BanSummary>,
+}
+
+/// Response to `POST /admin/trash/restore`
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RestoreResponse {
+    pub restored: u64,
+}
+
+/// Response to `POST /admin/retention`, with the number of messages deleted by each limit
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct RetentionResponse {
+    pub deleted: u64,
+    pub purged: u64,
+    pub expired: u64,
+    pub over_count: u64,
+    pub over_storage: u64,
+    pub batches: u64,
+    pub elapsed_ms: u64,
+}
+
+/// Response to the admin requests which change state, telling whether anything changed
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub struct AdminActionResponse {
+    pub changed: bool,
+}
+
+/// Settings of the inbox API server
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub port: u16,
+    /// Requests allowed from a single client IP address
+    pub ip_quota: Quota,
+    /// Requests allowed for a single inbox, across all clients
+    pub inbox_quota: Quota,
+    /// Origins allowed to call the API from a browser, e.g. `https://sorry.idont.date`.
+    /// `*` allows every origin, and an empty list disables CORS.
+    pub cors_origins: Vec<String>,
+    /// Secret from which inbox tokens are derived.
+    /// When set, reading or deleting an inbox requires its token.
+    pub token_secret: Option<String>,
+    /// Bearer token of the operator API under `/admin`, which is disabled when unset
+    pub admin_token: Option<String>,
+    /// Limits enforced by `POST /admin/retention`
+    pub retention: RetentionPolicy,
+    /// Messages listed per page unless the request asks for another `page_size`
+    pub page_size: u32,
+    /// Largest `page_size` accepted when listing an inbox
+    pub max_page_size: u32,
+    /// Time after which requests fail with a 504, and idle connections are closed
+    pub request_timeout: Duration,
+    /// Longest time `/inbox/wait` waits for mail, which must be below `request_timeout`
+    pub max_wait: Duration,
+}
+
+impl Config {
+    pub fn new(port: u16) -> Self {
+        Self {
+            port,
+            ip_quota: DEFAULT_IP_QUOTA,
+            inbox_quota: DEFAULT_INBOX_QUOTA,
+            cors_origins: Vec::new(),
+            token_secret: None,
+            admin_token: None,
+            retention: RetentionPolicy::default(),
+            page_size: DEFAULT_PAGE_SIZE,
+            max_page_size: DEFAULT_MAX_PAGE_SIZE,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_wait: DEFAULT_MAX_WAIT,
+        }
+    }
+
+    /// Returns the value of `Access-Control-Allow-Origin` for a request from `origin`,
+    /// or None if the origin is not allowed
+    fn cors_origin(&self, origin: &HeaderValue) -> Option<HeaderValue> {
+        let origin_str = origin.to_str().ok()?;
+        self.cors_origins
+            .iter()
+            .find(|allowed| {
+                *allowed == "*"
+                    || allowed
+                        .trim_end_matches('/')
+                        .eq_ignore_ascii_case(origin_str)
+            })
+            .map(|_| origin.clone())
+    }
+}
+
+/// State shared by all connections of the API server
+struct ApiContext {
+    config: RefCell<Rc<Config>>,
+    events: MailEvents,
+    limits: RefCell<RateLimits>,
+    /// Ends event streams, which would otherwise keep their connections open
+    shutdown: ShutdownSignal,
+}
+
+impl ApiContext {
+    fn new(config: Config, events: MailEvents, shutdown: ShutdownSignal) -> Self {
+        Self {
+            limits: RefCell::new(RateLimits::new(&config)),
+            config: RefCell::new(Rc::new(config)),
+            events,
+            shutdown,
+        }
+    }
+
+    /// Returns the current settings, which requests keep using until they finish
+    fn config(&self) -> Rc<Config> {
+        self.config.borrow().clone()
+    }
+
+    /// Applies new settings to requests from now on, except for the port,
+    /// which only changes on restart. Rate limit buckets keep their tokens.
+    fn reload(&self, config: Config) {
+        let mut limits = self.limits.borrow_mut();
+        limits.by_ip.set_quota(config.ip_quota);
+        limits.by_inbox.set_quota(config.inbox_quota);
+        *self.config.borrow_mut() = Rc::new(config);
+    }
+}
+
+/// Rate limiters shared by all API connections
+struct RateLimits {
+    by_ip: RateLimiter<IpAddr>,
+    by_inbox: RateLimiter<String>,
+}
+
+impl RateLimits {
+    fn new(config: &Config) -> Self {
+        Self {
+            by_ip: RateLimiter::new(config.ip_quota),
+            by_inbox: RateLimiter::new(config.inbox_quota),
+        }
+    }
+
+    /// Checks the limits of the client and of the requested inbox, if any.
+    /// Returns how long the client should wait if either limit is exceeded.
+    fn check(&mut self, ip: IpAddr, inbox: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        self.by_ip.check(ip, now)?;
+        match inbox {
+            Some(inbox) => self.by_inbox.check(inbox.to_lowercase(), now),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Serves the API on a thread of its own, applying every new config sent through `config`
+/// The returned thread finishes once the API has shut down.
+pub fn spawn(
+    config: watch::Receiver<Config>,
+    events: MailEvents,
+    shutdown: ShutdownSignal,
+) -> std::thread::JoinHandle<Result<()>> {
     std::thread::spawn(move || -> Result<()> {
         tokio::runtime::Builder::new_current_thread()
             .enable_io()
@@ -51,80 +351,268 @@ pub fn spawn(port: u16) {
                 let local = tokio::task::LocalSet::new();
                 local
                     .run_until(async move {
-                        if let Err(err) = serve(port).await {
+                        if let Err(err) = serve(config, events, shutdown).await {
                             tracing::error!("Inbox API failed: {}", err);
                         }
                     })
                     .await;
             });
         Ok(())
-    });
+    })
 }
 
-async fn serve(port: u16) -> Result<()> {
-    let addr = format!("0.0.0.0:{port}");
+async fn serve(
+    mut updates: watch::Receiver<Config>,
+    events: MailEvents,
+    shutdown: ShutdownSignal,
+) -> Result<()> {
+    let config = updates.borrow_and_update().clone();
+    let addr = format!("0.0.0.0:{}", config.port);
     let listener = TcpListener::bind(&addr).await?;
     tracing::info!("Inbox API listening on: {}", addr);
+    let context = Rc::new(ApiContext::new(config, events, shutdown.clone()));
+    tokio::task::spawn_local({
+        let context = context.clone();
+        async move {
+            while updates.changed().await.is_ok() {
+                context.reload(updates.borrow_and_update().clone());
+                tracing::info!("Inbox API settings reloaded");
+            }
+        }
+    });
+    serve_listener(listener, context, shutdown).await
+}
 
-    loop {
-        let (stream, peer) = listener.accept().await?;
+/// Serves connections until shutdown starts, then waits for the requests in progress
+/// to finish until the shutdown deadline
+async fn serve_listener(
+    listener: TcpListener,
+    context: Rc<ApiContext>,
+    mut shutdown: ShutdownSignal,
+) -> Result<()> {
+    let mut connections = tokio::task::JoinSet::new();
+    let deadline = loop {
+        let (stream, peer) = tokio::select! {
+            // Checked first, so that no connection is accepted after shutdown starts
+            biased;
+            deadline = shutdown.started() => break deadline,
+            accepted = listener.accept() => accepted?,
+        };
         tracing::debug!("Accepted API connection from {}", peer);
-        tokio::task::spawn_local(async move {
-            if let Err(err) = handle_connection(stream).await {
-                tracing::warn!("API request failed: {}", err);
+        while connections.try_join_next().is_some() {}
+        let context = context.clone();
+        let mut shutdown = shutdown.clone();
+        let request_timeout = context.config().request_timeout;
+        connections.spawn_local(async move {
+            let service = service_fn(move |request| {
+                let context = context.clone();
+                async move { Ok::<_, Infallible>(handle_request(request, peer.ip(), &context).await) }
+            });
+            // Keep-alive connections are closed once they stay idle for the request timeout
+            let connection = http1::Builder::new()
+                .timer(TokioTimer::new())
+                .header_read_timeout(request_timeout)
+                .serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            let result = tokio::select! {
+                result = connection.as_mut() => result,
+                _ = shutdown.started() => {
+                    // Lets the request in progress finish, then closes the connection
+                    connection.as_mut().graceful_shutdown();
+                    connection.await
+                }
+            };
+            if let Err(err) = result {
+                tracing::debug!("API connection from {peer} failed: {err}");
             }
         });
+    };
+    drop(listener);
+    tracing::info!("Inbox API stopped accepting connections");
+    let drained = timeout_at(deadline, async {
+        while connections.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        tracing::warn!(
+            "Closing {} API connections which did not finish before shutdown",
+            connections.len()
+        );
     }
+    Ok(())
 }
 
-async fn handle_connection(mut stream: TcpStream) -> Result<()> {
-    let request_number = SERVED_REQUESTS.fetch_add(1, Ordering::SeqCst);
-    if request_number >= MAX_SERVED_REQUESTS {
-        write_response(
-            &mut stream,
-            503,
-            &error_body("service unavailable: request limit reached"),
-        )
-        .await?;
-        return Ok(());
+async fn handle_request(
+    request: Request<Incoming>,
+    peer: IpAddr,
+    context: &ApiContext,
+) -> Response<Body> {
+    let cors_origin = request
+        .headers()
+        .get(ORIGIN)
+        .and_then(|origin| context.config().cors_origin(origin));
+    let mut response = if request.method() == Method::OPTIONS {
+        preflight_response(&request, cors_origin.is_some())
+    } else {
+        respond(request, peer, context).await
+    };
+    if let Some(origin) = cors_origin {
+        let headers = response.headers_mut();
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(
+            ACCESS_CONTROL_EXPOSE_HEADERS,
+            HeaderValue::from_static("Retry-After"),
+        );
+    }
+    if !context.config().cors_origins.is_empty() {
+        response
+            .headers_mut()
+            .append(VARY, HeaderValue::from_static("Origin"));
     }
+    response
+}
 
-    let mut buf = vec![0; 16 * 1024];
-    let n = stream.read(&mut buf).await?;
-    if n == 0 {
-        return Ok(());
+/// Answers an `OPTIONS` request, which browsers send as a CORS preflight
+fn preflight_response(request: &Request<Incoming>, cors_allowed: bool) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(ALLOW, ALLOWED_METHODS)
+        .body(Full::default().boxed_unsync())
+        .expect("static response parts are valid");
+    if cors_allowed {
+        let headers = response.headers_mut();
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            HeaderValue::from_static(ALLOWED_METHODS),
+        );
+        if let Some(requested) = request.headers().get(ACCESS_CONTROL_REQUEST_HEADERS) {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, requested.clone());
+        }
+        headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from(CORS_MAX_AGE));
+    }
+    response
+}
+
+async fn respond(request: Request<Incoming>, peer: IpAddr, context: &ApiContext) -> Response<Body> {
+    // HEAD is routed like GET, and hyper leaves out the body of the response
+    if ![
+        Method::GET,
+        Method::HEAD,
+        Method::POST,
+        Method::PATCH,
+        Method::DELETE,
+    ]
+    .contains(request.method())
+    {
+        let mut response = error_response(ApiError {
+            status: 405,
+            message: "method not allowed".to_string(),
+        });
+        response
+            .headers_mut()
+            .insert(ALLOW, HeaderValue::from_static(ALLOWED_METHODS));
+        return response;
     }
+    let (parts, body) = request.into_parts();
+    let path = parts.uri.path();
+    let query = parts.uri.query().unwrap_or_default();
 
-    let request = String::from_utf8_lossy(&buf[..n]);
-    let Some(request_line) = request.lines().next() else {
-        write_response(&mut stream, 400, &error_body("invalid request")).await?;
-        return Ok(());
+    // The bundled web client and the API description are served from memory,
+    // without counting against rate limits
+    if let Some(asset) = Asset::lookup(path) {
+        return asset_response(asset, parts.headers.get(IF_NONE_MATCH));
+    }
+    if path == "/openapi.json" {
+        return json_response(200, openapi::document().to_string());
+    }
+
+    let params = parse_query(query);
+    let admin = is_admin_path(path);
+    let authorized = if admin {
+        authorize_admin(&context.config(), &parts.headers)
+    } else {
+        authorize(&context.config(), &parts.headers, path, &params)
     };
+    // Operators are not rate limited, so that they can still reset the limits
+    if !(admin && authorized.is_ok()) {
+        let limited = context
+            .limits
+            .borrow_mut()
+            .check(peer, params.get("inbox").map(String::as_str));
+        if let Err(retry_after) = limited {
+            tracing::debug!("Rate limited API request from {peer}");
+            return rate_limited_response(retry_after);
+        }
+    }
+    if let Err(err) = authorized {
+        return error_response(err);
+    }
 
-    let mut parts = request_line.split_whitespace();
-    let method = parts.next().unwrap_or_default();
-    let target = parts.next().unwrap_or_default();
+    // Event streams stay open indefinitely, so they are not subject to the request timeout
+    if path == "/inbox/stream" {
+        let last_event_id = parts
+            .headers
+            .get("last-event-id")
+            .and_then(|value| value.to_str().ok());
+        return stream_inbox(query, last_event_id, &context.events, &context.shutdown)
+            .await
+            .unwrap_or_else(error_response);
+    }
 
-    if method != "GET" {
-        write_response(&mut stream, 405, &error_body("method not allowed")).await?;
-        return Ok(());
+    let routed = async {
+        let body = read_body(body).await?;
+        route_request(&parts.method, path, query, &body, context).await
+    };
+    match timeout(context.config().request_timeout, routed).await {
+        Ok(Ok(body)) => json_response(200, body),
+        Ok(Err(err)) => error_response(err),
+        Err(_) => error_response(ApiError {
+            status: 504,
+            message: "request timed out".to_string(),
+        }),
     }
+}
 
-    match timeout(REQUEST_TIMEOUT, route_request(target)).await {
-        Ok(Ok(body)) => write_response(&mut stream, 200, &body).await?,
-        Ok(Err(ApiError { status, message })) => {
-            write_response(&mut stream, status, &error_body(&message)).await?
+async fn read_body(body: Incoming) -> Result<Bytes, ApiError> {
+    match Limited::new(body, MAX_BODY_SIZE).collect().await {
+        Ok(collected) => Ok(collected.to_bytes()),
+        Err(err) if err.is::<LengthLimitError>() => Err(ApiError {
+            status: 413,
+            message: "request body too large".to_string(),
+        }),
+        Err(err) => {
+            tracing::debug!("Failed to read request body: {err}");
+            Err(ApiError::bad_request("invalid request body"))
         }
-        Err(_) => write_response(&mut stream, 504, &error_body("request timed out")).await?,
     }
-
-    Ok(())
 }
 
-async fn route_request(target: &str) -> Result<String, ApiError> {
-    let (path, query) = split_target(target);
-    match path {
-        "/inbox" => list_inbox(query).await,
+async fn route_request(
+    method: &Method,
+    path: &str,
+    query: &str,
+    body: &[u8],
+    context: &ApiContext,
+) -> Result<String, ApiError> {
+    if is_admin_path(path) {
+        return route_admin_request(method, &path["/admin".len()..], query, body, context).await;
+    }
+    match (method, path) {
+        (&Method::POST, "/inbox/claim") => claim_inbox(query, &context.config()).await,
+        (&Method::POST, _) => match ttl_inbox(path) {
+            Some(inbox) => set_inbox_ttl(&inbox, body).await,
+            None => Err(ApiError::not_found("not found")),
+        },
+        (&Method::PATCH, _) => match path.strip_prefix("/inbox/") {
+            Some(id) => update_inbox_message(id, body).await,
+            None => Err(ApiError::not_found("not found")),
+        },
+        (&Method::DELETE, "/inbox") => delete_inbox(query).await,
+        (&Method::DELETE, _) => match path.strip_prefix("/inbox/") {
+            Some(id) => delete_inbox_message(id).await,
+            None => Err(ApiError::not_found("not found")),
+        },
+        (_, "/inbox") => list_inbox(query, &context.config()).await,
+        (_, "/inbox/wait") => wait_for_mail(query, &context.events, &context.config()).await,
         _ => match path.strip_prefix("/inbox/") {
             Some(id) => get_inbox_message(id).await,
             None => Err(ApiError::not_found("not found")),
@@ -132,70 +620,827 @@ async fn route_request(target: &str) -> Result<String, ApiError> {
     }
 }
 
-async fn list_inbox(query: &str) -> Result<String, ApiError> {
+/// With inbox tokens enabled, requests for a whole inbox must carry its token, either as a bearer token
+/// or in the `token` parameter for clients such as `EventSource`, which cannot set headers.
+/// Single messages are reached through their unguessable ids instead.
+fn authorize(
+    config: &Config,
+    headers: &HeaderMap,
+    path: &str,
+    params: &HashMap<String, String>,
+) -> Result<(), ApiError> {
+    let Some(secret) = &config.token_secret else {
+        return Ok(());
+    };
+    let inbox = match path {
+        "/inbox" | "/inbox/wait" | "/inbox/stream" => required_inbox(params)?.to_string(),
+        _ => match ttl_inbox(path) {
+            Some(inbox) => inbox,
+            None => return Ok(()),
+        },
+    };
+    let token = bearer_token(headers).or(params.get("token").map(String::as_str));
+    match token {
+        Some(token) if verify_inbox_token(secret, &inbox, token) => Ok(()),
+        _ => Err(ApiError {
+            status: 401,
+            message: "missing or invalid inbox token".to_string(),
+        }),
+    }
+}
+
+/// Requests under `/admin` need the admin token, and are not found at all without `--admin-api`
+fn authorize_admin(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(admin_token) = &config.admin_token else {
+        return Err(ApiError::not_found("admin API is not enabled"));
+    };
+    match bearer_token(headers) {
+        Some(token) if verify_secret(admin_token, token) => Ok(()),
+        _ => Err(ApiError {
+            status: 401,
+            message: "missing or invalid admin token".to_string(),
+        }),
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+/// Routes operator requests, with `path` relative to `/admin`
+async fn route_admin_request(
+    method: &Method,
+    path: &str,
+    query: &str,
+    body: &[u8],
+    context: &ApiContext,
+) -> Result<String, ApiError> {
+    let db = Client::new().await?;
+    match (method, path) {
+        (&Method::GET | &Method::HEAD, "/inboxes" | "/trash") => {
+            let params = parse_query(query);
+            let page_size = page_size_param(&params, &context.config())?;
+            let after = match params.get("cursor") {
+                Some(cursor) => hex::decode(cursor)
+                    .ok()
+                    .and_then(|after| String::from_utf8(after).ok())
+                    .ok_or_else(|| ApiError::bad_request("invalid cursor"))?,
+                None => String::new(),
+            };
+            // One extra row tells whether there is another page
+            let mut counts = if path == "/trash" {
+                db.count_trash_by_inbox(&after, page_size + 1).await?
+            } else {
+                db.count_mail_by_inbox(&after, page_size + 1).await?
+            };
+            let has_more_pages = counts.len() > page_size as usize;
+            counts.truncate(page_size as usize);
+            let next_cursor = counts
+                .last()
+                .filter(|_| has_more_pages)
+                .map(|(inbox, _)| hex::encode(inbox));
+            let inboxes = counts
+                .into_iter()
+                .map(|(inbox, messages)| InboxCount { inbox, messages })
+                .collect();
+            Ok(serde_json::to_string(&InboxCountsResponse {
+                inboxes,
+                has_more_pages,
+                next_cursor,
+            })?)
+        }
+        (&Method::POST, "/trash/restore") => {
+            let params = parse_query(query);
+            let restored = match (params.get("id"), params.get("inbox")) {
+                (Some(id), None) => {
+                    let (rowid, key) = parse_message_id(id)
+                        .ok_or_else(|| ApiError::bad_request("invalid message id"))?;
+                    u64::from(db.restore_mail_by_id(rowid, key).await?)
+                }
+                (None, Some(inbox)) => db.restore_mail_by_recipient(inbox).await?,
+                _ => {
+                    return Err(ApiError::bad_request(
+                        "exactly one of id or inbox is required",
+                    ))
+                }
+            };
+            if restored == 0 {
+                return Err(ApiError::not_found("nothing to restore"));
+            }
+            tracing::info!("Restored {restored} messages from the trash through the admin API");
+            Ok(serde_json::to_string(&RestoreResponse { restored })?)
+        }
+        (&Method::GET | &Method::HEAD, "/storage") => {
+            let stats = db.storage_stats().await?;
+            Ok(serde_json::to_string(&StorageResponse {
+                messages: stats.messages,
+                trashed: stats.trashed,
+                mail_bytes: stats.mail_bytes,
+                database_bytes: stats.database_bytes,
+            })?)
+        }
+        (&Method::POST, "/retention") => {
+            let report = retention::enforce(&db, &context.config().retention).await?;
+            tracing::info!(
+                "Retention run through the admin API deleted {} messages",
+                report.deleted()
+            );
+            Ok(serde_json::to_string(&RetentionResponse {
+                deleted: report.deleted(),
+                purged: report.purged,
+                expired: report.expired,
+                over_count: report.over_count,
+                over_storage: report.over_storage,
+                batches: report.batches,
+                elapsed_ms: report.elapsed.as_millis() as u64,
+            })?)
+        }
+        (&Method::GET | &Method::HEAD, "/bans") => {
+            let bans = db
+                .list_bans()
+                .await?
+                .into_iter()
+                .map(|ban| BanSummary {
+                    kind: ban.kind,
+                    value: ban.value,
+                    created: ban.created,
+                })
+                .collect();
+            Ok(serde_json::to_string(&BansResponse { bans })?)
+        }
+        (&Method::POST, "/bans") => {
+            let request: BanRequest = serde_json::from_slice(body)
+                .map_err(|err| ApiError::bad_request(format!("invalid ban: {err}")))?;
+            let (kind, value) = parse_ban(request.ip.as_deref(), request.domain.as_deref())?;
+            let changed = db.add_ban(kind, &value).await?;
+            tracing::info!("Banned {} {value} through the admin API", kind.as_str());
+            Ok(serde_json::to_string(&AdminActionResponse { changed })?)
+        }
+        (&Method::DELETE, "/bans") => {
+            let params = parse_query(query);
+            let (kind, value) = parse_ban(
+                params.get("ip").map(String::as_str),
+                params.get("domain").map(String::as_str),
+            )?;
+            let changed = db.remove_ban(kind, &value).await?;
+            if !changed {
+                return Err(ApiError::not_found("ban not found"));
+            }
+            tracing::info!(
+                "Lifted ban of {} {value} through the admin API",
+                kind.as_str()
+            );
+            Ok(serde_json::to_string(&AdminActionResponse { changed })?)
+        }
+        (&Method::POST, "/rate-limits/reset") => {
+            let mut limits = context.limits.borrow_mut();
+            limits.by_ip.reset();
+            limits.by_inbox.reset();
+            tracing::info!("Rate limits were reset through the admin API");
+            Ok(serde_json::to_string(&AdminActionResponse {
+                changed: true,
+            })?)
+        }
+        _ => Err(ApiError::not_found("not found")),
+    }
+}
+
+/// Validates a ban given either as an IP address or as a domain such as `spam.example`,
+/// which also covers its subdomains
+fn parse_ban(ip: Option<&str>, domain: Option<&str>) -> Result<(BanKind, String), ApiError> {
+    match (ip, domain) {
+        (Some(ip), None) => ip
+            .trim()
+            .parse::<IpAddr>()
+            .map(|ip| (BanKind::Ip, ip.to_string()))
+            .map_err(|_| ApiError::bad_request("invalid IP address")),
+        (None, Some(domain)) => {
+            let domain = domain
+                .trim()
+                .trim_start_matches('@')
+                .trim_start_matches("*.")
+                .to_lowercase();
+            if domain.is_empty() || domain.contains(['@', '*', ' ']) {
+                return Err(ApiError::bad_request("invalid domain"));
+            }
+            Ok((BanKind::Domain, domain))
+        }
+        _ => Err(ApiError::bad_request(
+            "exactly one of ip or domain is required",
+        )),
+    }
+}
+
+/// Returns the inbox of a `/inbox/<inbox>/ttl` path
+fn ttl_inbox(path: &str) -> Option<String> {
+    let inbox = path.strip_prefix("/inbox/")?.strip_suffix("/ttl")?;
+    // Unlike in queries, `+` stands for itself in paths, as in `agent+signup@idont.date`
+    let inbox = decode_component(&inbox.replace('+', "%2B"));
+    inbox.contains('@').then_some(inbox)
+}
+
+/// Gives the inbox a lifetime of its own, which replaces the retention policy for its mail
+#[utoipa::path(
+    post,
+    path = "/inbox/{inbox}/ttl",
+    request_body = InboxTtlUpdate,
+    params(
+        ("inbox" = String, Path, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+    ),
+    responses(
+        (status = 200, description = "The new lifetime of the inbox", body = InboxTtlResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid inbox token", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn set_inbox_ttl(inbox: &str, body: &[u8]) -> Result<String, ApiError> {
+    let update: InboxTtlUpdate = serde_json::from_slice(body)
+        .map_err(|err| ApiError::bad_request(format!("invalid ttl update: {err}")))?;
+    let ttl = match update.ttl {
+        Some(ttl) => Some(
+            parse_duration(&ttl)
+                .ok()
+                .filter(|ttl| !ttl.is_zero())
+                .ok_or_else(|| ApiError::bad_request("ttl must be a duration like 1h or 7d"))?,
+        ),
+        None => None,
+    };
+    let db = Client::new().await?;
+    db.set_inbox_ttl(inbox, ttl).await?;
+    tracing::info!("Set the lifetime of {inbox} to {ttl:?} through the API");
+    let response = InboxTtlResponse {
+        inbox: inbox.to_string(),
+        ttl_seconds: ttl.map(|ttl| ttl.as_secs()),
+    };
+    serde_json::to_string(&response).map_err(Into::into)
+}
+
+/// Hands out the token of an inbox to whoever claims it first
+#[utoipa::path(
+    post,
+    path = "/inbox/claim",
+    params(
+        ("inbox" = String, Query, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+    ),
+    responses(
+        (status = 200, description = "The token of the inbox", body = ClaimResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 404, description = "The server does not use inbox tokens", body = ErrorResponse),
+        (status = 409, description = "The inbox has already been claimed", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn claim_inbox(query: &str, config: &Config) -> Result<String, ApiError> {
+    let Some(secret) = &config.token_secret else {
+        return Err(ApiError::not_found("inbox tokens are not enabled"));
+    };
     let params = parse_query(query);
-    let inbox = params
-        .get("inbox")
-        .filter(|value| !value.is_empty())
-        .ok_or_else(|| ApiError::bad_request("missing required query parameter: inbox"))?;
-    let page = match params.get("page") {
+    let inbox = required_inbox(&params)?;
+    let db = Client::new().await?;
+    if !db.claim_inbox(inbox).await? {
+        return Err(ApiError {
+            status: 409,
+            message: "inbox has already been claimed".to_string(),
+        });
+    }
+    tracing::info!("Inbox {inbox} was claimed through the API");
+    let response = ClaimResponse {
+        inbox: inbox.to_string(),
+        token: inbox_token(secret, inbox),
+    };
+    serde_json::to_string(&response).map_err(Into::into)
+}
+
+/// Lists the messages of an inbox, newest first, or the best search matches first with `q`
+#[utoipa::path(
+    get,
+    path = "/inbox",
+    params(
+        ("inbox" = String, Query, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+        ("page_size" = Option<u32>, Query, description = "Messages per page, 10 by default and at most 100"),
+        ("cursor" = Option<String>, Query, description = "`next_cursor` of the previous page"),
+        ("page" = Option<u64>, Query, description = "Page number starting at 1, instead of `cursor`"),
+        ("unread" = Option<bool>, Query, description = "Only messages which are (not) marked as seen"),
+        ("flagged" = Option<bool>, Query, description = "Only messages which are (not) flagged"),
+        ("sender" = Option<String>, Query, description = "Case-insensitive substring of the sender"),
+        ("subject" = Option<String>, Query, description = "Case-insensitive substring of the subject"),
+        ("q" = Option<String>, Query, description = "Words to search for in the subject, sender and body"),
+        ("since" = Option<String>, Query, description = "`YYYY-MM-DD` date or RFC 3339 timestamp of the oldest message"),
+        ("until" = Option<String>, Query, description = "`YYYY-MM-DD` date (inclusive) or RFC 3339 timestamp (exclusive) of the newest message"),
+        ("has_attachments" = Option<bool>, Query, description = "Only messages with (or without) attachments"),
+        ("token" = Option<String>, Query, description = "Inbox token, if the server requires tokens and the request carries no `Authorization` header"),
+    ),
+    responses(
+        (status = 200, description = "A page of messages", body = InboxListResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid inbox token", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn list_inbox(query: &str, config: &Config) -> Result<String, ApiError> {
+    let params = parse_query(query);
+    let inbox = required_inbox(&params)?;
+    let page_size = page_size_param(&params, config)?;
+    let filter = MailFilter {
+        seen: bool_param(&params, "unread")?.map(|unread| !unread),
+        flagged: bool_param(&params, "flagged")?,
+        sender: text_param(&params, "sender"),
+        subject: text_param(&params, "subject"),
+        since: date_param(&params, "since", false)?,
+        until: date_param(&params, "until", true)?,
+        has_attachments: bool_param(&params, "has_attachments")?,
+        text: text_param(&params, "q"),
+    };
+    let start = match (params.get("cursor"), params.get("page")) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request(
+                "cursor and page cannot be used together",
+            ))
+        }
+        (Some(cursor), None) => decode_cursor(cursor)
+            .filter(|start| filter.text.is_none() || matches!(start, PageStart::Offset(_)))
+            .ok_or_else(|| ApiError::bad_request("invalid cursor"))?,
+        (None, Some(page)) => {
+            let page = page
+                .parse::<u64>()
+                .ok()
+                .filter(|page| *page > 0)
+                .ok_or_else(|| ApiError::bad_request("page must be a positive integer"))?;
+            PageStart::Offset((page - 1).saturating_mul(u64::from(page_size)))
+        }
+        (None, None) => PageStart::default(),
+    };
+
+    let db = Client::new().await?;
+    // One extra row tells whether there is another page
+    let mut rows = db
+        .query_mail_by_recipient(inbox, &filter, &start, page_size + 1)
+        .await?;
+    let has_more_pages = rows.len() > page_size as usize;
+    rows.truncate(page_size as usize);
+    let next_cursor = match (rows.last(), &start) {
+        (Some(last), _) if has_more_pages && filter.text.is_none() => {
+            Some(encode_cursor(&PageStart::After {
+                date: last.date.clone(),
+                id: last.id,
+            }))
+        }
+        // Search results are ranked by relevance, so they can only be paged by offset
+        (Some(_), PageStart::Offset(offset)) if has_more_pages => Some(encode_cursor(
+            &PageStart::Offset(offset + u64::from(page_size)),
+        )),
+        _ => None,
+    };
+    let response = InboxListResponse {
+        mail: rows.iter().map(InboxMessageSummary::from).collect(),
+        has_more_pages,
+        next_cursor,
+    };
+    serde_json::to_string(&response).map_err(Into::into)
+}
+
+fn page_size_param(params: &HashMap<String, String>, config: &Config) -> Result<u32, ApiError> {
+    match params.get("page_size") {
         Some(value) => value
-            .parse::<usize>()
+            .parse::<u32>()
             .ok()
-            .filter(|page| *page > 0)
-            .ok_or_else(|| ApiError::bad_request("page must be a positive integer"))?,
-        None => 1,
+            .filter(|size| (1..=config.max_page_size).contains(size))
+            .ok_or_else(|| {
+                ApiError::bad_request(format!(
+                    "page_size must be between 1 and {}",
+                    config.max_page_size
+                ))
+            }),
+        None => Ok(config.page_size),
+    }
+}
+
+/// Encodes where the next page starts as an opaque, URL-safe cursor
+fn encode_cursor(start: &PageStart) -> String {
+    let cursor = match start {
+        PageStart::Offset(offset) => format!("o{offset}"),
+        PageStart::After { date, id } => format!("d{id}/{date}"),
+    };
+    hex::encode(cursor)
+}
+
+fn decode_cursor(cursor: &str) -> Option<PageStart> {
+    let cursor = String::from_utf8(hex::decode(cursor).ok()?).ok()?;
+    if let Some(offset) = cursor.strip_prefix('o') {
+        return offset.parse().ok().map(PageStart::Offset);
+    }
+    let (id, date) = cursor.strip_prefix('d')?.split_once('/')?;
+    Some(PageStart::After {
+        date: date.to_string(),
+        id: id.parse().ok()?,
+    })
+}
+
+/// Holds the request open until mail newer than `since` arrives for the inbox,
+/// or until the timeout passes, in which case an empty list is returned.
+#[utoipa::path(
+    get,
+    path = "/inbox/wait",
+    params(
+        ("inbox" = String, Query, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+        ("since" = Option<String>, Query, description = "Id of the newest message already seen; without it, only mail arriving after the call counts"),
+        ("timeout" = Option<u64>, Query, description = "Seconds to wait, at most 25 (the default)"),
+        ("token" = Option<String>, Query, description = "Inbox token, if the server requires tokens and the request carries no `Authorization` header"),
+    ),
+    responses(
+        (status = 200, description = "New messages, or an empty list after the timeout", body = InboxListResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid inbox token", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn wait_for_mail(
+    query: &str,
+    events: &MailEvents,
+    config: &Config,
+) -> Result<String, ApiError> {
+    let params = parse_query(query);
+    let inbox = required_inbox(&params)?;
+    let since = params
+        .get("since")
+        .map(|value| {
+            message_sequence(value)
+                .ok_or_else(|| ApiError::bad_request("since must be a message id"))
+        })
+        .transpose()?;
+    let max_wait = config.max_wait;
+    let wait = match params.get("timeout") {
+        Some(value) => value
+            .parse::<u64>()
+            .map(Duration::from_secs)
+            .map_err(|_| ApiError::bad_request("timeout must be a number of seconds"))?
+            .min(max_wait),
+        None => max_wait,
     };
+    let deadline = Instant::now() + wait;
+
     let db = Client::new().await?;
-    let rows = db.query_mail_by_recipient(inbox).await?;
-    let total = rows.len();
-    let start = (page - 1) * PAGE_SIZE;
-    let end = start.saturating_add(PAGE_SIZE).min(total);
-    let messages: Vec<InboxMessageSummary> = if start >= total {
-        Vec::new()
-    } else {
-        rows[start..end]
-            .iter()
-            .map(|record| {
-                let parsed = ParsedMail::from_raw(&record.data);
-                InboxMessageSummary {
-                    id: record.id,
-                    date: record.date.clone(),
-                    recipients: split_recipients(&record.recipients),
-                    sender: record.sender.clone(),
-                    subject: parsed.subject,
-                }
-            })
-            .collect()
+    // Without `since`, only mail arriving from now on counts
+    let since = match since {
+        Some(since) => since,
+        None => db.latest_mail_id().await?,
     };
+    // Subscribe before querying, so that mail stored in between is not missed
+    let mut receiver = events.subscribe();
+    // One more than a page tells whether there are more pages
+    let limit = Some(config.page_size + 1);
+    let mut rows = db.query_mail_after_id(inbox, since, limit).await?;
+    while rows.is_empty() {
+        match timeout_at(deadline, receiver.recv()).await {
+            Ok(Ok(record)) => {
+                if record.id > since && record.is_addressed_to(inbox) {
+                    rows.push(record);
+                }
+            }
+            Ok(Err(RecvError::Lagged(skipped))) => {
+                tracing::debug!("Waiting request lagged behind by {skipped} messages");
+                rows = db.query_mail_after_id(inbox, since, limit).await?;
+            }
+            Ok(Err(RecvError::Closed)) | Err(_) => break,
+        }
+    }
+    // Rows are newest first, and the oldest ones make up the page
+    let has_more_pages = rows.len() > config.page_size as usize;
+    if has_more_pages {
+        rows.remove(0);
+    }
     let response = InboxListResponse {
-        mail: messages,
-        has_more_pages: end < total,
+        mail: rows.iter().map(InboxMessageSummary::from).collect(),
+        has_more_pages,
+        next_cursor: None,
     };
     serde_json::to_string(&response).map_err(Into::into)
 }
 
+/// Streams a Server-Sent Event with a message summary for every new delivery to the inbox.
+/// Clients resuming with `Last-Event-ID` (or the `last_event_id` query parameter)
+/// first receive the mail they missed, oldest first.
+#[utoipa::path(
+    get,
+    path = "/inbox/stream",
+    params(
+        ("inbox" = String, Query, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+        ("last_event_id" = Option<String>, Query, description = "Id of the last received event, like the `Last-Event-ID` header"),
+        ("token" = Option<String>, Query, description = "Inbox token, if the server requires tokens and the request carries no `Authorization` header"),
+    ),
+    responses(
+        (status = 200, description = "Server-Sent Events with an `InboxMessageSummary` for every new message", content_type = "text/event-stream", body = String),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid inbox token", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn stream_inbox(
+    query: &str,
+    last_event_id: Option<&str>,
+    events: &MailEvents,
+    shutdown: &ShutdownSignal,
+) -> Result<Response<Body>, ApiError> {
+    let params = parse_query(query);
+    let inbox = required_inbox(&params)?.to_string();
+    let last_event_id = last_event_id.or(params.get("last_event_id").map(String::as_str));
+    let last_sent = last_event_id
+        .map(|id| {
+            message_sequence(id).ok_or_else(|| ApiError::bad_request("invalid last event id"))
+        })
+        .transpose()?;
+
+    let db = Client::new().await?;
+    // New clients start from the newest message, so that a lagging stream can catch up from there
+    let last_sent = match last_sent {
+        Some(id) => id,
+        None => db.latest_mail_id().await?,
+    };
+    // Subscribe before catching up, so that mail stored in between is not missed
+    let receiver = events.subscribe();
+    let backlog = db.query_mail_after_id(&inbox, last_sent, None).await?;
+
+    let (sender, chunks) = mpsc::channel(16);
+    let shutdown = shutdown.clone();
+    tokio::task::spawn_local(async move {
+        let stream = EventStream {
+            db,
+            inbox,
+            receiver,
+            sender,
+            shutdown,
+        };
+        if let Err(err) = stream.run(backlog, last_sent).await {
+            tracing::warn!("Event stream failed: {}", err);
+        }
+    });
+    let body = futures_util::stream::unfold(chunks, |mut chunks| async move {
+        let chunk = chunks.recv().await?;
+        Some((Ok(Frame::data(chunk)), chunks))
+    });
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(StreamBody::new(body).boxed_unsync())
+        .expect("static response parts are valid"))
+}
+
+/// Forwards mail for a single inbox to the body of an event stream response
+struct EventStream {
+    db: Client,
+    inbox: String,
+    receiver: broadcast::Receiver<MailRecord>,
+    sender: mpsc::Sender<Bytes>,
+    shutdown: ShutdownSignal,
+}
+
+impl EventStream {
+    /// Sends events until the client disconnects or shutdown starts
+    async fn run(mut self, mut backlog: Vec<MailRecord>, mut last_sent: i64) -> Result<()> {
+        let mut keepalive = tokio::time::interval(STREAM_KEEPALIVE_INTERVAL);
+        keepalive.tick().await;
+        loop {
+            // Backlog is sorted newest first, so popping yields the oldest message
+            while let Some(record) = backlog.pop() {
+                if record.id <= last_sent {
+                    continue;
+                }
+                if self.sender.send(sse_event(&record)?.into()).await.is_err() {
+                    return Ok(());
+                }
+                last_sent = record.id;
+            }
+            tokio::select! {
+                received = self.receiver.recv() => match received {
+                    Ok(record) => {
+                        if record.is_addressed_to(&self.inbox) {
+                            backlog.push(record);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::debug!("Event stream lagged behind by {skipped} messages");
+                        backlog = self.db.query_mail_after_id(&self.inbox, last_sent, None).await?;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = keepalive.tick() => {
+                    if self.sender.send(Bytes::from_static(b": keep-alive\n\n")).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                // Ends the response, so that the connection can close gracefully
+                _ = self.shutdown.started() => return Ok(()),
+            }
+        }
+    }
+}
+
+fn sse_event(record: &MailRecord) -> Result<String> {
+    let data = serde_json::to_string(&InboxMessageSummary::from(record))?;
+    Ok(format!("id: {}\ndata: {data}\n\n", record.public_id()))
+}
+
+/// Parses an optional boolean query parameter, accepting `true`/`false` and `1`/`0`
+fn bool_param(params: &HashMap<String, String>, name: &str) -> Result<Option<bool>, ApiError> {
+    match params.get(name).map(String::as_str) {
+        None => Ok(None),
+        Some("true" | "1") => Ok(Some(true)),
+        Some("false" | "0") => Ok(Some(false)),
+        Some(_) => Err(ApiError::bad_request(format!(
+            "{name} must be true or false"
+        ))),
+    }
+}
+
+/// Returns the parameter, unless it is missing or blank
+fn text_param(params: &HashMap<String, String>, name: &str) -> Option<String> {
+    params
+        .get(name)
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+        .map(ToOwned::to_owned)
+}
+
+/// Parses an RFC 3339 timestamp or a `YYYY-MM-DD` date in UTC.
+/// Bare dates mean the start of the day, or the end of it if `end_of_day` is set.
+fn date_param(
+    params: &HashMap<String, String>,
+    name: &str,
+    end_of_day: bool,
+) -> Result<Option<DateTime<Utc>>, ApiError> {
+    let Some(value) = params.get(name) else {
+        return Ok(None);
+    };
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(Some(time.with_timezone(&Utc)));
+    }
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| {
+        ApiError::bad_request(format!(
+            "{name} must be a date (YYYY-MM-DD) or an RFC 3339 timestamp"
+        ))
+    })?;
+    let date = if end_of_day {
+        date.succ_opt()
+            .ok_or_else(|| ApiError::bad_request(format!("{name} is out of range")))?
+    } else {
+        date
+    };
+    Ok(Some(date.and_time(NaiveTime::MIN).and_utc()))
+}
+
+fn required_inbox(params: &HashMap<String, String>) -> Result<&str, ApiError> {
+    params
+        .get("inbox")
+        .map(String::as_str)
+        .filter(|value| !value.is_empty())
+        .ok_or_else(|| ApiError::bad_request("missing required query parameter: inbox"))
+}
+
+/// Looks up a message by its public id, which only matches with the right key
+async fn find_message(db: &Client, id: &str) -> Result<MailRecord, ApiError> {
+    let (rowid, key) =
+        parse_message_id(id).ok_or_else(|| ApiError::bad_request("invalid message id"))?;
+    db.query_mail_by_id(rowid)
+        .await?
+        .filter(|record| !record.key.is_empty() && record.key == key)
+        .ok_or_else(|| ApiError::not_found("message not found"))
+}
+
+/// Splits a public message id into the rowid and the key
+fn parse_message_id(id: &str) -> Option<(i64, &str)> {
+    let (rowid, key) = id.split_once('-')?;
+    Some((rowid.parse().ok()?, key))
+}
+
+/// Returns the rowid of a public message id, which orders messages by arrival.
+/// Plain rowids are accepted as well.
+fn message_sequence(id: &str) -> Option<i64> {
+    id.split('-').next()?.parse().ok()
+}
+
+/// Returns a single message with its decoded body
+#[utoipa::path(
+    get,
+    path = "/inbox/{id}",
+    params(
+        ("id" = String, Path, description = "Full message id, as returned by the list endpoints"),
+    ),
+    responses(
+        (status = 200, description = "The message", body = InboxMessage),
+        (status = 404, description = "Message not found", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
 async fn get_inbox_message(id: &str) -> Result<String, ApiError> {
-    let id = id
-        .parse::<i64>()
-        .map_err(|_| ApiError::bad_request("invalid message id"))?;
     let db = Client::new().await?;
+    let record = find_message(&db, id).await?;
+    serde_json::to_string(&record_to_message(record)).map_err(Into::into)
+}
+
+/// Updates the seen and flagged state of a message and returns its summary
+#[utoipa::path(
+    patch,
+    path = "/inbox/{id}",
+    request_body = MessageFlagsUpdate,
+    params(
+        ("id" = String, Path, description = "Full message id, as returned by the list endpoints"),
+    ),
+    responses(
+        (status = 200, description = "The updated message", body = InboxMessageSummary),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 404, description = "Message not found", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn update_inbox_message(id: &str, body: &[u8]) -> Result<String, ApiError> {
+    let update: MessageFlagsUpdate = serde_json::from_slice(body)
+        .map_err(|err| ApiError::bad_request(format!("invalid message update: {err}")))?;
+    if update.seen.is_none() && update.flagged.is_none() {
+        return Err(ApiError::bad_request(
+            "message update must set seen or flagged",
+        ));
+    }
+    let db = Client::new().await?;
+    let id = find_message(&db, id).await?.id;
+    if !db
+        .update_mail_flags(id, update.seen, update.flagged)
+        .await?
+    {
+        return Err(ApiError::not_found("message not found"));
+    }
     let record = db
         .query_mail_by_id(id)
         .await?
         .ok_or_else(|| ApiError::not_found("message not found"))?;
-    serde_json::to_string(&record_to_message(record)).map_err(Into::into)
+    serde_json::to_string(&InboxMessageSummary::from(&record)).map_err(Into::into)
+}
+
+/// Deletes a single message, which operators can restore until the trash is purged
+#[utoipa::path(
+    delete,
+    path = "/inbox/{id}",
+    params(
+        ("id" = String, Path, description = "Full message id, as returned by the list endpoints"),
+    ),
+    responses(
+        (status = 200, description = "The message was deleted", body = DeleteResponse),
+        (status = 404, description = "Message not found", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn delete_inbox_message(id: &str) -> Result<String, ApiError> {
+    let db = Client::new().await?;
+    let id = find_message(&db, id).await?.id;
+    if !db.trash_mail_by_id(id).await? {
+        return Err(ApiError::not_found("message not found"));
+    }
+    tracing::info!("Deleted message {id} through the API");
+    serde_json::to_string(&DeleteResponse { deleted: 1 }).map_err(Into::into)
+}
+
+/// Deletes all mail addressed to the inbox, which operators can restore until the trash is purged
+#[utoipa::path(
+    delete,
+    path = "/inbox",
+    params(
+        ("inbox" = String, Query, description = "Exact address of the inbox, e.g. `agent@idont.date`"),
+        ("token" = Option<String>, Query, description = "Inbox token, if the server requires tokens and the request carries no `Authorization` header"),
+    ),
+    responses(
+        (status = 200, description = "Number of deleted messages", body = DeleteResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid inbox token", body = ErrorResponse),
+        (status = 429, description = "Rate limited; see the `Retry-After` header", body = ErrorResponse),
+    )
+)]
+async fn delete_inbox(query: &str) -> Result<String, ApiError> {
+    let params = parse_query(query);
+    let inbox = required_inbox(&params)?;
+    let db = Client::new().await?;
+    let deleted = db.trash_mail_by_recipient(inbox).await?;
+    tracing::info!("Deleted {deleted} messages for {inbox} through the API");
+    serde_json::to_string(&DeleteResponse { deleted }).map_err(Into::into)
 }
 
 fn record_to_message(record: MailRecord) -> InboxMessage {
-    let parsed = ParsedMail::from_raw(&record.data);
     InboxMessage {
-        id: record.id,
+        id: record.public_id(),
         date: record.date,
         recipients: split_recipients(&record.recipients),
+        body: DecodedMail::from_raw(&record.data).text,
+        source: record.data,
         sender: record.sender,
-        subject: parsed.subject,
-        body: parsed.body,
+        subject: record.subject,
+        seen: record.seen,
+        flagged: record.flagged,
     }
 }
 
@@ -208,55 +1453,6 @@ fn split_recipients(recipients: &str) -> Vec<String> {
         .collect()
 }
 
-#[derive(Debug, PartialEq, Eq)]
-struct ParsedMail {
-    subject: String,
-    body: String,
-}
-
-impl ParsedMail {
-    fn from_raw(raw: &str) -> Self {
-        let normalized = raw.replace("\r\n", "\n");
-        let (headers, body) = match normalized.split_once("\n\n") {
-            Some((headers, body)) => (headers, body),
-            None => ("", normalized.as_str()),
-        };
-        let subject = unfolded_headers(headers)
-            .into_iter()
-            .find_map(|header| {
-                header
-                    .strip_prefix("Subject:")
-                    .or_else(|| header.strip_prefix("subject:"))
-                    .map(|value| value.trim().to_string())
-            })
-            .unwrap_or_default();
-
-        Self {
-            subject,
-            body: body.to_string(),
-        }
-    }
-}
-
-fn unfolded_headers(headers: &str) -> Vec<String> {
-    let mut unfolded: Vec<String> = Vec::new();
-    for line in headers.lines() {
-        if line.starts_with(' ') || line.starts_with('\t') {
-            if let Some(last) = unfolded.last_mut() {
-                last.push(' ');
-                last.push_str(line.trim());
-            }
-        } else {
-            unfolded.push(line.to_string());
-        }
-    }
-    unfolded
-}
-
-fn split_target(target: &str) -> (&str, &str) {
-    target.split_once('?').unwrap_or((target, ""))
-}
-
 fn parse_query(query: &str) -> HashMap<String, String> {
     query
         .split('&')
@@ -296,30 +1492,68 @@ fn decode_component(value: &str) -> String {
     decoded
 }
 
-async fn write_response(stream: &mut TcpStream, status: u16, body: &str) -> Result<()> {
-    let status_text = match status {
-        200 => "OK",
-        400 => "Bad Request",
-        404 => "Not Found",
-        405 => "Method Not Allowed",
-        500 => "Internal Server Error",
-        503 => "Service Unavailable",
-        504 => "Gateway Timeout",
-        _ => "Error",
+/// Serves a file of the web client, or 304 if the client's cached copy is still current
+fn asset_response(asset: Asset, if_none_match: Option<&HeaderValue>) -> Response<Body> {
+    let etag = asset.etag();
+    let cached = if_none_match
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| {
+            tags.split(',')
+                .any(|tag| tag.trim() == etag || tag.trim() == "*")
+        });
+    let (status, body) = if cached {
+        (StatusCode::NOT_MODIFIED, Full::default())
+    } else {
+        (
+            StatusCode::OK,
+            Full::new(Bytes::from_static(asset.contents)),
+        )
     };
-    let response = format!(
-        "HTTP/1.1 {status} {status_text}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
-        body.len(),
-        body
-    );
-    stream.write_all(response.as_bytes()).await?;
-    Ok(())
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, asset.content_type)
+        .header(CACHE_CONTROL, asset.cache_control())
+        .header(ETAG, etag)
+        .body(body.boxed_unsync())
+        .expect("static response parts are valid")
+}
+
+fn json_response(status: u16, body: String) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        .header(CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)).boxed_unsync())
+        .expect("static response parts are valid")
+}
+
+fn error_response(err: ApiError) -> Response<Body> {
+    let mut response = json_response(err.status, error_body(&err.message));
+    if err.status == 401 {
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+    }
+    response
+}
+
+/// Responds with 429, telling the client how many seconds to wait before retrying
+fn rate_limited_response(retry_after: Duration) -> Response<Body> {
+    let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    let mut response = json_response(429, error_body("too many requests"));
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+    response
 }
 
 fn error_body(message: &str) -> String {
-    serde_json::json!({ "error": message }).to_string()
+    let body = ErrorResponse {
+        error: message.to_string(),
+    };
+    serde_json::to_string(&body).expect("error responses are serializable")
 }
 
+#[derive(Debug)]
 struct ApiError {
     status: u16,
     message: String,
@@ -364,51 +1598,705 @@ impl From<serde_json::Error> for ApiError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::shutdown::Shutdown;
+
+    #[test]
+    fn decodes_query_components() {
+        let params = parse_query("inbox=agent%40example.com&unused=hello+world");
+        assert_eq!(params.get("inbox"), Some(&"agent@example.com".to_string()));
+        assert_eq!(params.get("unused"), Some(&"hello world".to_string()));
+    }
+
+    #[test]
+    fn round_trips_cursors() {
+        for start in [
+            PageStart::Offset(20),
+            PageStart::After {
+                date: "2026-05-18 10:00:00.000".to_string(),
+                id: 42,
+            },
+        ] {
+            let cursor = encode_cursor(&start);
+            assert!(cursor.chars().all(|c| c.is_ascii_alphanumeric()));
+            assert_eq!(decode_cursor(&cursor), Some(start));
+        }
+        assert_eq!(decode_cursor("not a cursor"), None);
+        assert_eq!(decode_cursor(&hex::encode("d42")), None);
+    }
+
+    #[test]
+    fn parses_date_range_params() {
+        let params =
+            parse_query("since=2026-05-18&until=2026-05-18&at=2026-05-18T12%3A30%3A00%2B02%3A00");
+        let since = date_param(&params, "since", false).unwrap().unwrap();
+        let until = date_param(&params, "until", true).unwrap().unwrap();
+        let at = date_param(&params, "at", false).unwrap().unwrap();
+        assert_eq!(since.to_rfc3339(), "2026-05-18T00:00:00+00:00");
+        assert_eq!(until.to_rfc3339(), "2026-05-19T00:00:00+00:00");
+        assert_eq!(at.to_rfc3339(), "2026-05-18T10:30:00+00:00");
+        assert_eq!(date_param(&params, "missing", false).unwrap(), None);
+        let invalid = parse_query("since=yesterday");
+        assert_eq!(
+            date_param(&invalid, "since", false).unwrap_err().status,
+            400
+        );
+    }
+
+    /// Reads a single response from a keep-alive connection
+    async fn read_response(stream: &mut tokio::net::TcpStream, with_body: bool) -> String {
+        use tokio::io::AsyncReadExt;
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let text = String::from_utf8_lossy(&response).to_string();
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .to_lowercase()
+                    .lines()
+                    .find_map(|line| line.strip_prefix("content-length:")?.trim().parse().ok())
+                    .unwrap_or(0);
+                if !with_body || body.len() >= length {
+                    return text;
+                }
+            }
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed early");
+            response.extend_from_slice(&buf[..n]);
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_split_requests_over_keep_alive() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::database::use_test_database();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let shutdown = Shutdown::new();
+                let context =
+                    ApiContext::new(Config::new(0), MailEvents::new(), shutdown.subscribe());
+                let server = tokio::task::spawn_local(serve_listener(
+                    listener,
+                    Rc::new(context),
+                    shutdown.subscribe(),
+                ));
+                let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                client
+                    .write_all(b"GET /inbox?inbox=split%40idont.date HT")
+                    .await
+                    .unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client
+                    .write_all(b"TP/1.1\r\nHost: localhost\r\n\r\n")
+                    .await
+                    .unwrap();
+                let first = read_response(&mut client, true).await;
+                assert!(first.starts_with("HTTP/1.1 200 OK"), "{first}");
+                assert!(
+                    first.ends_with("{\"mail\":[],\"has_more_pages\":false,\"next_cursor\":null}")
+                );
+
+                client
+                    .write_all(
+                        b"HEAD /inbox?inbox=split%40idont.date HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let second = read_response(&mut client, false).await;
+                assert!(second.starts_with("HTTP/1.1 200 OK"), "{second}");
+                assert!(
+                    second.to_lowercase().contains("content-length: 53"),
+                    "{second}"
+                );
+                assert!(second.ends_with("\r\n\r\n"));
+
+                client
+                    .write_all(
+                        b"PUT /inbox HTTP/1.1\r\nHost: localhost\r\nContent-Length: 0\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let third = read_response(&mut client, true).await;
+                assert!(third.starts_with("HTTP/1.1 405"), "{third}");
+
+                // Shutting down closes idle connections and stops the server
+                shutdown.start(Instant::now() + Duration::from_secs(5));
+                server.await.unwrap().unwrap();
+                let mut rest = Vec::new();
+                client.read_to_end(&mut rest).await.unwrap();
+                assert!(rest.is_empty());
+                assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn shutdown_ends_open_event_streams() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::database::use_test_database();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let shutdown = Shutdown::new();
+                let context = ApiContext::new(Config::new(0), MailEvents::new(), shutdown.subscribe());
+                let server = tokio::task::spawn_local(serve_listener(
+                    listener,
+                    Rc::new(context),
+                    shutdown.subscribe(),
+                ));
+                let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+                client
+                    .write_all(
+                        b"GET /inbox/stream?inbox=streamed%40idont.date HTTP/1.1\r\nHost: localhost\r\n\r\n",
+                    )
+                    .await
+                    .unwrap();
+                let headers = read_response(&mut client, false).await;
+                assert!(headers.starts_with("HTTP/1.1 200 OK"), "{headers}");
+
+                // The server stops long before the deadline, instead of waiting for the stream
+                shutdown.start(Instant::now() + Duration::from_secs(30));
+                tokio::time::timeout(Duration::from_secs(5), server)
+                    .await
+                    .expect("server waited for the event stream")
+                    .unwrap()
+                    .unwrap();
+                let mut rest = Vec::new();
+                client.read_to_end(&mut rest).await.unwrap();
+                // The chunked body is terminated properly
+                assert!(rest.ends_with(b"0\r\n\r\n"), "{rest:?}");
+            })
+            .await;
+    }
+
+    #[test]
+    fn revalidates_cached_assets() {
+        let asset = Asset::lookup("/inbox.js").unwrap();
+        let fresh = asset_response(asset, None);
+        assert_eq!(fresh.status(), StatusCode::OK);
+        let etag = fresh.headers().get(ETAG).unwrap().clone();
+
+        let cached = asset_response(asset, Some(&etag));
+        assert_eq!(cached.status(), StatusCode::NOT_MODIFIED);
+        let stale = asset_response(asset, Some(&HeaderValue::from_static("\"outdated\"")));
+        assert_eq!(stale.status(), StatusCode::OK);
+    }
 
     #[test]
-    fn parses_subject_and_body() {
-        let parsed = ParsedMail::from_raw(
-            "Subject: Test message\r\nFrom: sender@example.com\r\n\r\nHello world\r\nSecond line",
+    fn allows_only_listed_cors_origins() {
+        let mut config = Config::new(0);
+        let origin = HeaderValue::from_static("https://sorry.idont.date");
+        assert_eq!(config.cors_origin(&origin), None);
+        config.cors_origins = vec!["https://sorry.idont.date/".to_string()];
+        assert_eq!(config.cors_origin(&origin), Some(origin.clone()));
+        assert_eq!(
+            config.cors_origin(&HeaderValue::from_static("https://evil.example")),
+            None
+        );
+        config.cors_origins = vec!["*".to_string()];
+        assert_eq!(
+            config.cors_origin(&HeaderValue::from_static("https://evil.example")),
+            Some(HeaderValue::from_static("https://evil.example"))
         );
-        assert_eq!(parsed.subject, "Test message");
-        assert_eq!(parsed.body, "Hello world\nSecond line");
     }
 
     #[test]
-    fn unfolds_continued_subject_lines() {
-        let parsed = ParsedMail::from_raw("Subject: Long\r\n subject\r\n\r\nBody");
-        assert_eq!(parsed.subject, "Long subject");
+    fn limits_clients_and_inboxes_separately() {
+        let mut limits = RateLimits::new(&Config {
+            ip_quota: Quota::per_minute(2),
+            inbox_quota: Quota::per_minute(1),
+            ..Config::new(0)
+        });
+        let first = IpAddr::from([10, 0, 0, 1]);
+        let second = IpAddr::from([10, 0, 0, 2]);
+        assert!(limits.check(first, Some("a@idont.date")).is_ok());
+        assert!(limits.check(second, Some("A@idont.date")).is_err());
+        assert!(limits.check(second, Some("b@idont.date")).is_ok());
+        assert!(limits.check(first, None).is_ok());
+        assert!(limits.check(first, None).is_err());
     }
 
     #[test]
-    fn returns_empty_subject_when_missing() {
-        let parsed = ParsedMail::from_raw("From: sender@example.com\r\n\r\nHello world");
-        assert_eq!(parsed.subject, "");
+    fn reloads_settings_and_limits() {
+        let context = ApiContext::new(
+            Config {
+                ip_quota: Quota::per_minute(1),
+                ..Config::new(0)
+            },
+            MailEvents::new(),
+            Shutdown::new().subscribe(),
+        );
+        let client = IpAddr::from([10, 0, 0, 3]);
+        let in_flight = context.config();
+        assert!(context.limits.borrow_mut().check(client, None).is_ok());
+        assert!(context.limits.borrow_mut().check(client, None).is_err());
+
+        context.reload(Config {
+            ip_quota: Quota::per_minute(6000),
+            page_size: 5,
+            ..Config::new(0)
+        });
+        assert_eq!(context.config().page_size, 5);
+        assert_eq!(in_flight.page_size, DEFAULT_PAGE_SIZE);
+        // The client's bucket refills at the new rate
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(context.limits.borrow_mut().check(client, None).is_ok());
     }
 
     #[test]
-    fn decodes_query_components() {
-        let params = parse_query("inbox=agent%40example.com&unused=hello+world");
-        assert_eq!(params.get("inbox"), Some(&"agent@example.com".to_string()));
-        assert_eq!(params.get("unused"), Some(&"hello world".to_string()));
+    fn parses_ttl_paths() {
+        assert_eq!(
+            ttl_inbox("/inbox/agent+qa%40idont.date/ttl").as_deref(),
+            Some("agent+qa@idont.date")
+        );
+        assert_eq!(ttl_inbox("/inbox/42-0f1e/ttl"), None);
+        assert_eq!(ttl_inbox("/inbox/agent@idont.date"), None);
+    }
+
+    #[test]
+    fn parses_public_message_ids() {
+        assert_eq!(parse_message_id("42-0f1e"), Some((42, "0f1e")));
+        assert_eq!(parse_message_id("42"), None);
+        assert_eq!(parse_message_id("x-0f1e"), None);
+        assert_eq!(message_sequence("42-0f1e"), Some(42));
+        assert_eq!(message_sequence("42"), Some(42));
+        assert_eq!(message_sequence("latest"), None);
+    }
+
+    #[test]
+    fn requires_inbox_tokens_when_enabled() {
+        let params = parse_query("inbox=agent%40idont.date");
+        let mut headers = HeaderMap::new();
+        let open = Config::new(0);
+        assert!(authorize(&open, &headers, "/inbox", &params).is_ok());
+
+        let config = Config {
+            token_secret: Some("s3cret".to_string()),
+            ..Config::new(0)
+        };
+        let err = authorize(&config, &headers, "/inbox/stream", &params).unwrap_err();
+        assert_eq!(err.status, 401);
+        // Messages are protected by their ids, and claiming needs no token
+        assert!(authorize(&config, &headers, "/inbox/1-0f1e", &params).is_ok());
+        assert!(authorize(&config, &headers, "/inbox/claim", &params).is_ok());
+
+        let token = inbox_token("s3cret", "agent@idont.date");
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {token}")).unwrap(),
+        );
+        assert!(authorize(&config, &headers, "/inbox", &params).is_ok());
+        let other = parse_query("inbox=other%40idont.date");
+        assert!(authorize(&config, &headers, "/inbox", &other).is_err());
+
+        let ttl_path = "/inbox/agent%40idont.date/ttl";
+        assert!(authorize(&config, &headers, ttl_path, &HashMap::new()).is_ok());
+        let other_ttl_path = "/inbox/other%40idont.date/ttl";
+        assert!(authorize(&config, &headers, other_ttl_path, &HashMap::new()).is_err());
+
+        let in_query = parse_query(&format!("inbox=agent%40idont.date&token={token}"));
+        assert!(authorize(&config, &HeaderMap::new(), "/inbox/stream", &in_query).is_ok());
+    }
+
+    #[test]
+    fn requires_admin_token() {
+        let mut headers = HeaderMap::new();
+        let err = authorize_admin(&Config::new(0), &headers).unwrap_err();
+        assert_eq!(err.status, 404);
+
+        let config = Config {
+            admin_token: Some("admin-s3cret".to_string()),
+            ..Config::new(0)
+        };
+        assert_eq!(authorize_admin(&config, &headers).unwrap_err().status, 401);
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("Bearer wrong"));
+        assert_eq!(authorize_admin(&config, &headers).unwrap_err().status, 401);
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_static("Bearer admin-s3cret"),
+        );
+        assert!(authorize_admin(&config, &headers).is_ok());
+
+        assert!(is_admin_path("/admin/bans"));
+        assert!(!is_admin_path("/administrator"));
+    }
+
+    #[test]
+    fn parses_bans() {
+        assert_eq!(
+            parse_ban(Some(" 192.0.2.1 "), None).unwrap(),
+            (BanKind::Ip, "192.0.2.1".to_string())
+        );
+        assert_eq!(
+            parse_ban(None, Some("*.Spam.Example")).unwrap(),
+            (BanKind::Domain, "spam.example".to_string())
+        );
+        assert!(parse_ban(Some("not an ip"), None).is_err());
+        assert!(parse_ban(None, Some("a@b")).is_err());
+        assert!(parse_ban(Some("192.0.2.1"), Some("spam.example")).is_err());
+        assert!(parse_ban(None, None).is_err());
+    }
+
+    #[test]
+    fn formats_server_sent_events() {
+        let record = MailRecord {
+            id: 7,
+            date: "2026-05-18 10:00:00.000".to_string(),
+            sender: "<noreply@example.com>".to_string(),
+            recipients: "<a@idont.date>".to_string(),
+            data: "Subject: Hi\r\n\r\nBody".to_string(),
+            subject: "Hi".to_string(),
+            seen: false,
+            flagged: false,
+            key: "0f1e".to_string(),
+        };
+        assert_eq!(
+            sse_event(&record).unwrap(),
+            "id: 7-0f1e\ndata: {\"id\":\"7-0f1e\",\"date\":\"2026-05-18 10:00:00.000\",\"recipients\":[\"<a@idont.date>\"],\"sender\":\"<noreply@example.com>\",\"subject\":\"Hi\",\"seen\":false,\"flagged\":false}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_returns_mail_published_while_waiting() {
+        crate::database::use_test_database();
+        let events = MailEvents::new();
+        let deliver = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let db = Client::new().await.unwrap();
+            let record = crate::database::store_test_mail(
+                &db,
+                "<waiter@idont.date>",
+                "Subject: Wake up\r\n\r\nBody",
+            )
+            .await;
+            events.publish(record);
+        };
+        let config = Config::new(0);
+        let (body, _) = tokio::join!(
+            wait_for_mail("inbox=waiter%40idont.date&timeout=5", &events, &config),
+            deliver
+        );
+        let body = body.ok().unwrap();
+        assert!(body.contains("\"subject\":\"Wake up\""), "{body}");
+    }
+
+    #[tokio::test]
+    async fn wait_without_since_only_counts_new_mail() {
+        crate::database::use_test_database();
+        let db = Client::new().await.unwrap();
+        let mut ids = Vec::new();
+        for n in 0..3 {
+            let record = crate::database::store_test_mail(
+                &db,
+                "<waitpage@idont.date>",
+                &format!("Subject: Message {n}\r\n\r\nBody"),
+            )
+            .await;
+            ids.push(record.id);
+        }
+        let events = MailEvents::new();
+        let config = Config {
+            page_size: 2,
+            ..Config::new(0)
+        };
+
+        // Mail stored before the call does not end the wait
+        let body = wait_for_mail("inbox=waitpage%40idont.date&timeout=0", &events, &config)
+            .await
+            .ok()
+            .unwrap();
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["mail"], serde_json::json!([]));
+
+        // With `since`, at most a page of the oldest new messages is returned
+        let query = format!("inbox=waitpage%40idont.date&timeout=0&since={}", ids[0] - 1);
+        let body = wait_for_mail(&query, &events, &config).await.ok().unwrap();
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        let subjects: Vec<_> = response["mail"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|mail| mail["subject"].as_str().unwrap())
+            .collect();
+        assert_eq!(subjects, ["Message 1", "Message 0"]);
+        assert_eq!(response["has_more_pages"], true);
+    }
+
+    #[tokio::test]
+    async fn stream_catches_up_after_lagging_without_last_event_id() {
+        crate::database::use_test_database();
+        let db = Client::new().await.unwrap();
+        let last_sent = db.latest_mail_id().await.unwrap();
+        // A channel of one message lags as soon as two are published
+        let (publisher, receiver) = broadcast::channel(1);
+        let mut ids = Vec::new();
+        for n in 0..3 {
+            let record = crate::database::store_test_mail(
+                &db,
+                "<lagging@idont.date>",
+                &format!("Subject: Message {n}\r\n\r\nBody"),
+            )
+            .await;
+            ids.push(record.public_id());
+            publisher.send(record).unwrap();
+        }
+        let (sender, mut chunks) = mpsc::channel(16);
+        let stream = EventStream {
+            db,
+            inbox: "lagging@idont.date".to_string(),
+            receiver,
+            sender,
+            shutdown: Shutdown::new().subscribe(),
+        };
+        let received = async {
+            let mut received = Vec::new();
+            while received.len() < ids.len() {
+                let chunk = chunks.recv().await.unwrap();
+                let chunk = String::from_utf8(chunk.to_vec()).unwrap();
+                received.push(chunk.lines().next().unwrap().to_string());
+            }
+            received
+        };
+        let received = tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                received = received => received,
+                result = stream.run(Vec::new(), last_sent) => panic!("stream ended: {result:?}"),
+            }
+        })
+        .await
+        .expect("stream missed the skipped messages");
+        let expected: Vec<_> = ids.iter().map(|id| format!("id: {id}")).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn returns_the_decoded_subject_and_body() {
+        crate::database::use_test_database();
+        let db = Client::new().await.unwrap();
+        let data = "Subject: =?utf-8?B?WmHFvMOzxYLEhyBnxJnFm2zEhQ==?=\r\n\
+                    Content-Type: text/plain; charset=utf-8\r\n\
+                    Content-Transfer-Encoding: quoted-printable\r\n\
+                    \r\n\
+                    ja=C5=BA=C5=84\r\n";
+        crate::database::store_test_mail(&db, "<encoded@idont.date>", data).await;
+        let query = "inbox=encoded%40idont.date&subject=za";
+        let body = list_inbox(query, &Config::new(0)).await.ok().unwrap();
+        let response: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(response["mail"][0]["subject"], "Zażółć gęślą", "{body}");
+        let id = response["mail"][0]["id"].as_str().unwrap();
+        let message = get_inbox_message(id).await.ok().unwrap();
+        let message: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(message["subject"], "Zażółć gęślą");
+        assert_eq!(message["body"], "jaźń");
+        assert_eq!(message["source"], data);
+    }
+
+    /// Routes a request without a body and returns the JSON response or the error status
+    async fn call(
+        context: &ApiContext,
+        method: Method,
+        path: &str,
+        query: &str,
+    ) -> Result<serde_json::Value, u16> {
+        route_request(&method, path, query, b"", context)
+            .await
+            .map(|body| serde_json::from_str(&body).unwrap())
+            .map_err(|err| err.status)
+    }
+
+    #[tokio::test]
+    async fn trashes_and_restores_mail_through_the_api() {
+        crate::database::use_test_database();
+        let db = Client::new().await.unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let record = crate::database::store_test_mail(
+                &db,
+                "<admin_trash@idont.date>",
+                "Subject: Evidence\r\n\r\nBody",
+            )
+            .await;
+            ids.push(record.public_id());
+        }
+        let mut config = Config::new(0);
+        config.retention.max_age_rules = vec![(
+            crate::pattern::InboxPattern::new("admin_trash@idont.date"),
+            Duration::ZERO,
+        )];
+        let context = ApiContext::new(config, MailEvents::new(), Shutdown::new().subscribe());
+        let inbox = "inbox=admin_trash%40idont.date";
+        // Pages through the shared trash, one inbox at a time
+        let trashed = || async {
+            let mut query = "page_size=1".to_string();
+            loop {
+                let trash = call(&context, Method::GET, "/admin/trash", &query)
+                    .await
+                    .unwrap();
+                let counts = trash["inboxes"].as_array().unwrap();
+                assert!(counts.len() <= 1, "{trash}");
+                if let Some(count) = counts
+                    .iter()
+                    .find(|count| count["inbox"] == "admin_trash@idont.date")
+                {
+                    return count["messages"].as_u64().unwrap();
+                }
+                match trash["next_cursor"].as_str() {
+                    Some(cursor) => query = format!("page_size=1&cursor={cursor}"),
+                    None => return 0,
+                }
+            }
+        };
+
+        let message = format!("/inbox/{}", ids[0]);
+        let deleted = call(&context, Method::DELETE, &message, "").await;
+        assert_eq!(deleted.unwrap()["deleted"], 1);
+        assert_eq!(call(&context, Method::GET, &message, "").await, Err(404));
+        assert_eq!(trashed().await, 1);
+        let restored = call(
+            &context,
+            Method::POST,
+            "/admin/trash/restore",
+            &format!("id={}", ids[0]),
+        )
+        .await;
+        assert_eq!(restored.unwrap()["restored"], 1);
+        assert!(call(&context, Method::GET, &message, "").await.is_ok());
+
+        // The retention job moves expired mail to the trash as well
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let report = call(&context, Method::POST, "/admin/retention", "")
+            .await
+            .unwrap();
+        assert!(report["expired"].as_u64().unwrap() >= 3, "{report}");
+        assert_eq!(trashed().await, 3);
+        let listed = call(&context, Method::GET, "/inbox", inbox).await.unwrap();
+        assert_eq!(listed["mail"], serde_json::json!([]));
+        let restored = call(&context, Method::POST, "/admin/trash/restore", inbox).await;
+        assert_eq!(restored.unwrap()["restored"], 3);
+
+        let deleted = call(&context, Method::DELETE, "/inbox", inbox).await;
+        assert_eq!(deleted.unwrap()["deleted"], 3);
+        let restored = call(&context, Method::POST, "/admin/trash/restore", inbox).await;
+        assert_eq!(restored.unwrap()["restored"], 3);
+        let restored = call(&context, Method::POST, "/admin/trash/restore", inbox).await;
+        assert_eq!(restored, Err(404));
+        assert_eq!(trashed().await, 0);
+    }
+
+    #[tokio::test]
+    async fn wait_times_out_with_empty_list() {
+        crate::database::use_test_database();
+        let events = MailEvents::new();
+        let body = wait_for_mail(
+            "inbox=nobody%40idont.date&timeout=0",
+            &events,
+            &Config::new(0),
+        )
+        .await
+        .ok()
+        .unwrap();
+        assert_eq!(
+            body,
+            "{\"mail\":[],\"has_more_pages\":false,\"next_cursor\":null}"
+        );
+    }
+
+    #[tokio::test]
+    async fn routes_every_documented_operation() {
+        crate::database::use_test_database();
+        let config = Config {
+            token_secret: Some("s3cret".to_string()),
+            ..Config::new(0)
+        };
+        let context = ApiContext::new(config, MailEvents::new(), Shutdown::new().subscribe());
+        let document: serde_json::Value = serde_json::from_str(openapi::document()).unwrap();
+        let paths = document["paths"].as_object().unwrap();
+        assert!(paths.len() >= 5);
+        for (path, operations) in paths {
+            // Event streams are answered before requests are routed
+            if path == "/inbox/stream" {
+                continue;
+            }
+            let path = path
+                .replace("{id}", "0-0f1e")
+                .replace("{inbox}", "openapi_drift%40idont.date");
+            for method in operations.as_object().unwrap().keys() {
+                let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+                let result = route_request(
+                    &method,
+                    &path,
+                    "inbox=openapi_drift%40idont.date&timeout=0",
+                    b"{\"seen\": true}",
+                    &context,
+                )
+                .await;
+                if let Err(err) = result {
+                    assert_ne!(err.message, "not found", "{method} {path} is not routed");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn documents_response_types() {
+        let document: serde_json::Value = serde_json::from_str(openapi::document()).unwrap();
+        let schemas = &document["components"]["schemas"];
+        let message = InboxMessage {
+            id: "1-0f1e".to_string(),
+            date: "2026-05-18 10:00:00.000".to_string(),
+            recipients: vec![],
+            sender: String::new(),
+            subject: String::new(),
+            body: String::new(),
+            source: String::new(),
+            seen: false,
+            flagged: false,
+        };
+        let list = InboxListResponse {
+            mail: vec![],
+            has_more_pages: false,
+            next_cursor: None,
+        };
+        for (name, example) in [
+            ("InboxMessage", serde_json::to_value(message).unwrap()),
+            ("InboxListResponse", serde_json::to_value(list).unwrap()),
+        ] {
+            let mut documented: Vec<_> = schemas[name]["properties"]
+                .as_object()
+                .unwrap_or_else(|| panic!("{name} is not documented"))
+                .keys()
+                .collect();
+            let mut serialized: Vec<_> = example.as_object().unwrap().keys().collect();
+            documented.sort();
+            serialized.sort();
+            assert_eq!(documented, serialized, "{name}");
+        }
+        assert!(schemas["InboxMessageSummary"].is_object());
     }
 
     #[test]
     fn serializes_paginated_list_response_shape() {
         let response = InboxListResponse {
             mail: vec![InboxMessageSummary {
-                id: 1,
+                id: "1-0f1e".to_string(),
                 date: "2026-05-18 10:00:00.000".to_string(),
                 recipients: vec!["<a@idont.date>".to_string()],
                 sender: "<noreply@example.com>".to_string(),
                 subject: "Hello".to_string(),
+                seen: false,
+                flagged: true,
             }],
             has_more_pages: true,
+            next_cursor: Some("6f3130".to_string()),
         };
         let json = serde_json::to_string(&response).unwrap();
         assert_eq!(
             json,
-            "{\"mail\":[{\"id\":1,\"date\":\"2026-05-18 10:00:00.000\",\"recipients\":[\"<a@idont.date>\"],\"sender\":\"<noreply@example.com>\",\"subject\":\"Hello\"}],\"has_more_pages\":true}"
+            "{\"mail\":[{\"id\":\"1-0f1e\",\"date\":\"2026-05-18 10:00:00.000\",\"recipients\":[\"<a@idont.date>\"],\"sender\":\"<noreply@example.com>\",\"subject\":\"Hello\",\"seen\":false,\"flagged\":true}],\"has_more_pages\":true,\"next_cursor\":\"6f3130\"}"
         );
     }
 }
@@ -0,0 +1,84 @@
+use sha2::{Digest, Sha256};
+
+/// A file of the web client from the `client/` directory, built into the binary
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Asset {
+    pub content_type: &'static str,
+    pub contents: &'static [u8],
+}
+
+macro_rules! asset {
+    ($name:literal, $content_type:literal) => {
+        (
+            concat!("/", $name),
+            Asset {
+                content_type: $content_type,
+                contents: include_bytes!(concat!("../client/", $name)),
+            },
+        )
+    };
+}
+
+const ASSETS: &[(&str, Asset)] = &[
+    asset!("index.html", "text/html; charset=utf-8"),
+    asset!("inbox.html", "text/html; charset=utf-8"),
+    asset!("login.js", "text/javascript; charset=utf-8"),
+    asset!("inbox.js", "text/javascript; charset=utf-8"),
+    asset!("favicon.ico", "image/x-icon"),
+    asset!("favicon-16x16.png", "image/png"),
+    asset!("favicon-32x32.png", "image/png"),
+    asset!("apple-touch-icon.png", "image/png"),
+    asset!("android-chrome-192x192.png", "image/png"),
+    asset!("android-chrome-512x512.png", "image/png"),
+];
+
+impl Asset {
+    /// Finds the asset served at the given path, with `/` serving `index.html`
+    pub fn lookup(path: &str) -> Option<Self> {
+        let path = if path == "/" { "/index.html" } else { path };
+        ASSETS
+            .iter()
+            .find(|(asset_path, _)| *asset_path == path)
+            .map(|(_, asset)| *asset)
+    }
+
+    /// A strong entity tag derived from the contents
+    pub fn etag(&self) -> String {
+        let digest = Sha256::digest(self.contents);
+        format!("\"{}\"", hex::encode(&digest[..16]))
+    }
+
+    /// Pages are revalidated on every load, so that they always match the API they talk to,
+    /// while scripts and images may be cached for a while
+    pub fn cache_control(&self) -> &'static str {
+        if self.content_type.starts_with("text/html") {
+            "no-cache"
+        } else {
+            "public, max-age=3600"
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serves_index_at_root() {
+        let index = Asset::lookup("/").unwrap();
+        assert_eq!(index, Asset::lookup("/index.html").unwrap());
+        assert_eq!(index.content_type, "text/html; charset=utf-8");
+        assert_eq!(index.cache_control(), "no-cache");
+        assert!(Asset::lookup("/inbox").is_none());
+        assert!(Asset::lookup("/../Cargo.toml").is_none());
+    }
+
+    #[test]
+    fn etags_differ_between_files() {
+        let login = Asset::lookup("/login.js").unwrap();
+        let inbox = Asset::lookup("/inbox.js").unwrap();
+        assert_eq!(login.etag(), login.etag());
+        assert_ne!(login.etag(), inbox.etag());
+        assert_eq!(login.cache_control(), "public, max-age=3600");
+    }
+}
@@ -0,0 +1,63 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+/// Derives the bearer token of an inbox from the server secret.
+/// Tokens are never stored: the server derives them again to check a request.
+pub fn inbox_token(secret: &str, inbox: &str) -> String {
+    hex::encode(inbox_mac(secret, inbox).finalize().into_bytes())
+}
+
+/// Checks a bearer token against the inbox, in constant time
+pub fn verify_inbox_token(secret: &str, inbox: &str, token: &str) -> bool {
+    match hex::decode(token.trim()) {
+        Ok(token) => inbox_mac(secret, inbox).verify_slice(&token).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// Compares a token supplied by a client with a configured secret.
+/// Both are hashed first, so that the comparison takes the same time for any input.
+pub fn verify_secret(secret: &str, token: &str) -> bool {
+    let expected = Sha256::digest(secret.as_bytes());
+    let given = Sha256::digest(token.trim().as_bytes());
+    expected
+        .iter()
+        .zip(given.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn inbox_mac(secret: &str, inbox: &str) -> Hmac<Sha256> {
+    let inbox = inbox
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_lowercase();
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(inbox.as_bytes());
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_tokens_per_inbox() {
+        let token = inbox_token("s3cret", "agent@idont.date");
+        assert_eq!(token.len(), 64);
+        assert!(verify_inbox_token("s3cret", "<Agent@idont.date>", &token));
+        assert!(!verify_inbox_token("s3cret", "agent2@idont.date", &token));
+        assert!(!verify_inbox_token("other", "agent@idont.date", &token));
+        assert!(!verify_inbox_token("s3cret", "agent@idont.date", "not hex"));
+        assert!(!verify_inbox_token("s3cret", "agent@idont.date", ""));
+    }
+
+    #[test]
+    fn verifies_secrets() {
+        assert!(verify_secret("admin-s3cret", "admin-s3cret"));
+        assert!(!verify_secret("admin-s3cret", "admin-s3cre"));
+        assert!(!verify_secret("admin-s3cret", ""));
+    }
+}
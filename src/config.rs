@@ -0,0 +1,629 @@
+//! Settings of the server, read from a TOML file, environment variables and command line flags,
+//! where each layer overrides the ones before it.
+
+use crate::pattern::InboxPattern;
+use crate::ratelimit::Quota;
+use crate::retention::{self, RetentionPolicy};
+use crate::webhooks::Webhook;
+use crate::{api, smtp};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Duration;
+use toml::{Table, Value};
+
+/// Environment variable naming the config file, unless `--config` is given
+pub const CONFIG_ENV: &str = "EDGEMAIL_CONFIG";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Integer,
+    Bool,
+    /// Comma-separated in environment variables
+    List,
+}
+
+/// Settings which can be given through environment variables or command line flags,
+/// by their dotted path in the config file
+const KEYS: &[(&str, Kind)] = &[
+    ("database_url", Kind::Text),
+    ("webhooks", Kind::List),
+    ("shutdown_timeout", Kind::Text),
+    ("smtp.addr", Kind::Text),
+    ("smtp.domain", Kind::Text),
+    ("smtp.session_timeout", Kind::Text),
+    ("smtp.blocked_recipients", Kind::List),
+    ("smtp.max_message_size", Kind::Text),
+    ("api.port", Kind::Integer),
+    ("api.ip_rate_limit", Kind::Text),
+    ("api.inbox_rate_limit", Kind::Text),
+    ("api.cors_origins", Kind::List),
+    ("api.require_tokens", Kind::Bool),
+    ("api.admin", Kind::Bool),
+    ("api.page_size", Kind::Integer),
+    ("api.max_page_size", Kind::Integer),
+    ("api.request_timeout", Kind::Text),
+    ("api.max_wait", Kind::Text),
+    ("retention.max_age", Kind::Text),
+    ("retention.max_messages", Kind::Integer),
+    ("retention.max_storage", Kind::Text),
+    ("retention.trash_period", Kind::Text),
+    ("retention.cleanup_interval", Kind::Text),
+];
+
+/// Returns the environment variable overriding a setting, e.g. `EDGEMAIL_API_PAGE_SIZE`.
+/// The database URL keeps the variable read by the database client.
+pub fn env_var(key: &str) -> String {
+    if key == "database_url" {
+        return "LIBSQL_CLIENT_URL".to_string();
+    }
+    format!("EDGEMAIL_{}", key.replace('.', "_").to_uppercase())
+}
+
+/// The effective settings, with durations, sizes and rate limits still in their text form
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Database to store mail in, a local one if unset
+    pub database_url: Option<String>,
+    /// `[PATTERN=]URL` of every webhook
+    pub webhooks: Vec<String>,
+    /// Time given to sessions and requests in progress to finish when shutting down
+    pub shutdown_timeout: String,
+    pub smtp: SmtpSettings,
+    pub api: ApiSettings,
+    pub retention: RetentionSettings,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SmtpSettings {
+    pub addr: String,
+    pub domain: String,
+    /// Time after which an SMTP session is closed
+    pub session_timeout: String,
+    /// Patterns of recipients whose mail is not accepted
+    pub blocked_recipients: Vec<String>,
+    pub max_message_size: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiSettings {
+    /// Port of the inbox API, which is disabled if unset
+    pub port: Option<u16>,
+    pub ip_rate_limit: String,
+    pub inbox_rate_limit: String,
+    pub cors_origins: Vec<String>,
+    pub require_tokens: bool,
+    /// Whether to serve the operator API under `/admin`
+    pub admin: bool,
+    pub page_size: u32,
+    pub max_page_size: u32,
+    pub request_timeout: String,
+    /// Longest time `/inbox/wait` waits for mail
+    pub max_wait: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionSettings {
+    pub max_age: String,
+    pub max_messages: Option<u64>,
+    pub max_storage: Option<String>,
+    pub trash_period: String,
+    pub cleanup_interval: String,
+    /// Limits of inboxes matching a pattern, where the first matching rule wins
+    pub rules: Vec<RetentionRule>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRule {
+    pub pattern: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_age: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_messages: Option<u64>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            database_url: None,
+            webhooks: Vec::new(),
+            shutdown_timeout: "10s".to_string(),
+            smtp: SmtpSettings::default(),
+            api: ApiSettings::default(),
+            retention: RetentionSettings::default(),
+        }
+    }
+}
+
+impl Default for SmtpSettings {
+    fn default() -> Self {
+        Self {
+            addr: "0.0.0.0:2525".to_string(),
+            domain: "smtp.idont.date".to_string(),
+            session_timeout: "5m".to_string(),
+            blocked_recipients: smtp::DEFAULT_BLOCKED_RECIPIENTS
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            max_message_size: "10MB".to_string(),
+        }
+    }
+}
+
+impl Default for ApiSettings {
+    fn default() -> Self {
+        Self {
+            port: None,
+            ip_rate_limit: "60/min".to_string(),
+            inbox_rate_limit: "120/min".to_string(),
+            cors_origins: Vec::new(),
+            require_tokens: false,
+            admin: false,
+            page_size: api::DEFAULT_PAGE_SIZE,
+            max_page_size: api::DEFAULT_MAX_PAGE_SIZE,
+            request_timeout: "30s".to_string(),
+            max_wait: "25s".to_string(),
+        }
+    }
+}
+
+impl Default for RetentionSettings {
+    fn default() -> Self {
+        Self {
+            max_age: "7d".to_string(),
+            max_messages: None,
+            max_storage: None,
+            trash_period: "7d".to_string(),
+            cleanup_interval: "1h".to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl RetentionRule {
+    /// Parses a rule given as `PATTERN=DURATION`, as in `--max-age`
+    pub fn max_age(spec: &str) -> Option<Self> {
+        let (pattern, max_age) = spec.split_once('=')?;
+        Some(Self {
+            pattern: pattern.to_string(),
+            max_age: Some(max_age.to_string()),
+            max_messages: None,
+        })
+    }
+
+    /// Parses a rule given as `PATTERN=N`, as in `--max-messages`
+    pub fn max_messages(spec: &str) -> Result<Option<Self>> {
+        let Some((pattern, count)) = spec.split_once('=') else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            pattern: pattern.to_string(),
+            max_age: None,
+            max_messages: Some(parse_count(count)?),
+        }))
+    }
+}
+
+impl Settings {
+    /// Checks every setting, so that mistakes are reported at startup
+    pub fn validate(&self) -> Result<()> {
+        self.smtp_config()?;
+        self.retention_policy()?;
+        self.webhooks()?;
+        self.api_config(None, None)?;
+        self.shutdown_timeout()?;
+        Ok(())
+    }
+
+    pub fn shutdown_timeout(&self) -> Result<Duration> {
+        positive_duration(&self.shutdown_timeout, "shutdown_timeout")
+    }
+
+    pub fn smtp_config(&self) -> Result<smtp::Config> {
+        let max_message_size = retention::parse_size(&self.smtp.max_message_size)
+            .context("invalid smtp.max_message_size")?;
+        anyhow::ensure!(
+            max_message_size > 0,
+            "smtp.max_message_size must be larger than 0"
+        );
+        Ok(smtp::Config {
+            domain: self.smtp.domain.clone(),
+            session_timeout: positive_duration(&self.smtp.session_timeout, "smtp.session_timeout")?,
+            blocked_recipients: self
+                .smtp
+                .blocked_recipients
+                .iter()
+                .map(InboxPattern::new)
+                .collect(),
+            max_message_size,
+        })
+    }
+
+    /// Returns the settings which differ from `other` but only take effect after a restart,
+    /// since they decide what the server binds to, connects to or runs
+    pub fn restart_required(&self, other: &Settings) -> Vec<&'static str> {
+        [
+            ("database_url", self.database_url != other.database_url),
+            ("webhooks", self.webhooks != other.webhooks),
+            ("smtp.addr", self.smtp.addr != other.smtp.addr),
+            ("api.port", self.api.port != other.api.port),
+        ]
+        .into_iter()
+        .filter_map(|(key, changed)| changed.then_some(key))
+        .collect()
+    }
+
+    pub fn retention_policy(&self) -> Result<RetentionPolicy> {
+        let settings = &self.retention;
+        let mut policy = RetentionPolicy {
+            max_age: duration(&settings.max_age, "retention.max_age")?,
+            max_messages: settings.max_messages,
+            trash_period: duration(&settings.trash_period, "retention.trash_period")?,
+            interval: positive_duration(&settings.cleanup_interval, "retention.cleanup_interval")?,
+            ..RetentionPolicy::default()
+        };
+        if let Some(size) = &settings.max_storage {
+            policy.max_storage =
+                Some(retention::parse_size(size).context("invalid retention.max_storage")?);
+        }
+        for rule in &settings.rules {
+            anyhow::ensure!(
+                rule.max_age.is_some() || rule.max_messages.is_some(),
+                "retention rule for {} sets neither max_age nor max_messages",
+                rule.pattern
+            );
+            let pattern = InboxPattern::new(&rule.pattern);
+            if let Some(age) = &rule.max_age {
+                let age = duration(age, "max_age of a retention rule")?;
+                policy.max_age_rules.push((pattern.clone(), age));
+            }
+            if let Some(count) = rule.max_messages {
+                policy.max_messages_rules.push((pattern, count));
+            }
+        }
+        Ok(policy)
+    }
+
+    pub fn webhooks(&self) -> Result<Vec<Webhook>> {
+        self.webhooks
+            .iter()
+            .map(|spec| Webhook::parse(spec).context("invalid webhook"))
+            .collect()
+    }
+
+    /// Returns the settings of the inbox API, or None if it is disabled
+    pub fn api_config(
+        &self,
+        token_secret: Option<String>,
+        admin_token: Option<String>,
+    ) -> Result<Option<api::Config>> {
+        let settings = &self.api;
+        let Some(port) = settings.port else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            (1..=settings.max_page_size).contains(&settings.page_size),
+            "api.page_size must be between 1 and api.max_page_size ({})",
+            settings.max_page_size
+        );
+        let request_timeout = positive_duration(&settings.request_timeout, "api.request_timeout")?;
+        let max_wait = positive_duration(&settings.max_wait, "api.max_wait")?;
+        // Long-polling requests finish with an empty response rather than a 504
+        anyhow::ensure!(
+            max_wait < request_timeout,
+            "api.max_wait must be shorter than api.request_timeout"
+        );
+        Ok(Some(api::Config {
+            ip_quota: quota(&settings.ip_rate_limit, "api.ip_rate_limit")?,
+            inbox_quota: quota(&settings.inbox_rate_limit, "api.inbox_rate_limit")?,
+            cors_origins: settings.cors_origins.clone(),
+            token_secret,
+            admin_token,
+            retention: self.retention_policy()?,
+            page_size: settings.page_size,
+            max_page_size: settings.max_page_size,
+            request_timeout,
+            max_wait,
+            ..api::Config::new(port)
+        }))
+    }
+
+    /// Returns the settings as a config file
+    pub fn to_toml(&self) -> Result<String> {
+        toml::to_string(self).context("failed to print the settings")
+    }
+}
+
+/// Settings put together from layers, each of which overrides the ones before it
+#[derive(Debug, Default)]
+pub struct Layers {
+    table: Table,
+}
+
+impl Layers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the config file at `path`
+    pub fn file(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        self.toml(&text)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Adds settings given as TOML
+    pub fn toml(&mut self, text: &str) -> Result<()> {
+        let table: Table = text.parse()?;
+        merge(&mut self.table, table);
+        Ok(())
+    }
+
+    /// Adds the settings found in environment variables, looked up with `var`
+    pub fn env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<()> {
+        for (key, _) in KEYS {
+            let name = env_var(key);
+            if let Some(value) = var(&name) {
+                self.set(key, &value)
+                    .with_context(|| format!("invalid value for {name}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets a single setting from its text form, where lists are separated by commas
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let (_, kind) = KEYS
+            .iter()
+            .find(|(known, _)| *known == key)
+            .with_context(|| format!("unknown setting: {key}"))?;
+        let value = match kind {
+            Kind::Text => Value::String(value.to_string()),
+            Kind::Integer => Value::Integer(value.trim().parse().context("must be a number")?),
+            Kind::Bool => Value::Boolean(value.trim().parse().context("must be true or false")?),
+            Kind::List => Value::Array(
+                value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .map(|item| Value::String(item.to_string()))
+                    .collect(),
+            ),
+        };
+        self.insert(key, value);
+        Ok(())
+    }
+
+    /// Replaces a list setting
+    pub fn set_list(&mut self, key: &str, values: Vec<String>) {
+        self.insert(
+            key,
+            Value::Array(values.into_iter().map(Value::String).collect()),
+        );
+    }
+
+    /// Adds retention rules in front of those of earlier layers, so that they take precedence
+    pub fn prepend_rules(&mut self, rules: Vec<RetentionRule>) -> Result<()> {
+        let retention = self
+            .table
+            .entry("retention")
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .context("retention must be a table")?;
+        let mut all = rules
+            .into_iter()
+            .map(Value::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(earlier) = retention.remove("rules") {
+            let Value::Array(earlier) = earlier else {
+                anyhow::bail!("retention.rules must be a list");
+            };
+            all.extend(earlier);
+        }
+        retention.insert("rules".to_string(), Value::Array(all));
+        Ok(())
+    }
+
+    /// Returns the resulting settings, after checking every one of them
+    pub fn settings(self) -> Result<Settings> {
+        let settings =
+            Settings::deserialize(Value::Table(self.table)).context("invalid settings")?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn insert(&mut self, key: &str, value: Value) {
+        let mut table = &mut self.table;
+        let mut parts: Vec<&str> = key.split('.').collect();
+        let last = parts.pop().expect("keys are not empty");
+        for part in parts {
+            let entry = table
+                .entry(part)
+                .or_insert_with(|| Value::Table(Table::new()));
+            if !entry.is_table() {
+                *entry = Value::Table(Table::new());
+            }
+            table = entry.as_table_mut().expect("entry is a table");
+        }
+        table.insert(last.to_string(), value);
+    }
+}
+
+/// Merges `layer` into `base`, replacing everything but tables, which are merged key by key
+fn merge(base: &mut Table, layer: Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+fn duration(value: &str, key: &str) -> Result<Duration> {
+    retention::parse_duration(value).with_context(|| format!("invalid {key}"))
+}
+
+fn positive_duration(value: &str, key: &str) -> Result<Duration> {
+    let duration = duration(value, key)?;
+    anyhow::ensure!(!duration.is_zero(), "{key} must be longer than 0s");
+    Ok(duration)
+}
+
+fn quota(value: &str, key: &str) -> Result<Quota> {
+    value.parse().with_context(|| format!("invalid {key}"))
+}
+
+fn parse_count(count: &str) -> Result<u64> {
+    count
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid message count: {count}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_match_modules() {
+        let settings = Settings::default();
+        settings.validate().unwrap();
+        assert_eq!(
+            settings.retention_policy().unwrap(),
+            RetentionPolicy::default()
+        );
+        assert_eq!(
+            settings.smtp_config().unwrap(),
+            smtp::Config::new("smtp.idont.date")
+        );
+        assert_eq!(settings.api_config(None, None).unwrap(), None);
+        assert_eq!(
+            settings.shutdown_timeout().unwrap(),
+            Duration::from_secs(10)
+        );
+
+        let mut settings = settings;
+        settings.api.port = Some(8080);
+        assert_eq!(
+            settings.api_config(None, None).unwrap(),
+            Some(api::Config::new(8080))
+        );
+    }
+
+    #[test]
+    fn layers_override_each_other() {
+        let mut layers = Layers::new();
+        layers
+            .toml(
+                r#"
+                webhooks = ["https://example.com/hook"]
+
+                [smtp]
+                domain = "mx.example.com"
+                addr = "127.0.0.1:25"
+
+                [api]
+                port = 8080
+                page_size = 20
+
+                [[retention.rules]]
+                pattern = "qa_*@example.com"
+                max_age = "30d"
+                "#,
+            )
+            .unwrap();
+        layers
+            .env(|name| match name {
+                "EDGEMAIL_SMTP_DOMAIN" => Some("env.example.com".to_string()),
+                "EDGEMAIL_API_PAGE_SIZE" => Some("30".to_string()),
+                "EDGEMAIL_API_CORS_ORIGINS" => {
+                    Some("https://a.example, https://b.example".to_string())
+                }
+                "LIBSQL_CLIENT_URL" => Some("file:///tmp/mail.db".to_string()),
+                _ => None,
+            })
+            .unwrap();
+        layers.set("smtp.domain", "cli.example.com").unwrap();
+        layers
+            .prepend_rules(vec![RetentionRule::max_messages("*@example.com=5")
+                .unwrap()
+                .unwrap()])
+            .unwrap();
+        let settings = layers.settings().unwrap();
+
+        assert_eq!(settings.smtp.domain, "cli.example.com");
+        assert_eq!(settings.smtp.addr, "127.0.0.1:25");
+        assert_eq!(settings.smtp.session_timeout, "5m");
+        assert_eq!(settings.api.page_size, 30);
+        assert_eq!(
+            settings.api.cors_origins,
+            ["https://a.example", "https://b.example"]
+        );
+        assert_eq!(
+            settings.database_url.as_deref(),
+            Some("file:///tmp/mail.db")
+        );
+        assert_eq!(settings.webhooks, ["https://example.com/hook"]);
+
+        let policy = settings.retention_policy().unwrap();
+        assert_eq!(policy.max_messages_for("qa_login@example.com"), Some(5));
+        assert_eq!(
+            policy.max_age_for("qa_login@example.com"),
+            Duration::from_secs(30 * 24 * 3600)
+        );
+
+        // Printed settings read back the same
+        let mut reread = Layers::new();
+        reread.toml(&settings.to_toml().unwrap()).unwrap();
+        assert_eq!(reread.settings().unwrap(), settings);
+
+        let mut changed = settings.clone();
+        changed.smtp.domain = "new.example.com".to_string();
+        changed.api.page_size = 5;
+        assert!(settings.restart_required(&changed).is_empty());
+        changed.api.port = None;
+        changed.database_url = None;
+        assert_eq!(
+            settings.restart_required(&changed),
+            ["database_url", "api.port"]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let invalid = |text: &str| {
+            let mut layers = Layers::new();
+            layers.toml(text).and_then(|()| layers.settings()).is_err()
+        };
+        assert!(invalid("typo = 1"));
+        assert!(invalid("[smtp]\nport = 25"));
+        assert!(invalid("[api]\nport = \"eighty\""));
+        assert!(invalid("[api]\nport = 8080\npage_size = 0"));
+        assert!(invalid("[api]\nport = 8080\npage_size = 200"));
+        assert!(invalid("[api]\nport = 8080\nmax_wait = \"1m\""));
+        assert!(invalid("[api]\nport = 8080\nip_rate_limit = \"lots\""));
+        assert!(invalid("[retention]\nmax_age = \"forever\""));
+        assert!(invalid("[retention]\ncleanup_interval = \"0s\""));
+        assert!(invalid("shutdown_timeout = \"0s\""));
+        assert!(invalid("[smtp]\nmax_message_size = \"huge\""));
+        assert!(invalid("[smtp]\nmax_message_size = \"0\""));
+        assert!(invalid("[[retention.rules]]\npattern = \"*@example.com\""));
+        assert!(!invalid("[retention]\nmax_storage = \"1G\""));
+
+        let mut layers = Layers::new();
+        assert!(layers.set("api.port", "eighty").is_err());
+        assert!(layers.set("smtp.nope", "1").is_err());
+        assert!(layers
+            .env(|name| (name == "EDGEMAIL_API_ADMIN").then(|| "yes".to_string()))
+            .is_err());
+    }
+}
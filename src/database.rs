@@ -1,6 +1,7 @@
+use crate::message::DecodedMail;
 use crate::smtp::Mail;
 use anyhow::{Context, Result};
-use libsql_client::{client::GenericClient, DatabaseClient, Statement, Value};
+use libsql_client::{client::GenericClient, DatabaseClient, ResultSet, Statement, Value};
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct MailRecord {
@@ -9,87 +10,1154 @@ pub struct MailRecord {
     pub sender: String,
     pub recipients: String,
     pub data: String,
+    /// Subject with MIME encoded words decoded
+    pub subject: String,
+    pub seen: bool,
+    pub flagged: bool,
+    /// Random secret which makes the public id of the message unguessable
+    pub key: String,
+}
+
+/// Optional conditions narrowing down mail returned by `Client::query_mail_by_recipient`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MailFilter {
+    pub seen: Option<bool>,
+    pub flagged: Option<bool>,
+    /// Case-insensitive substring of the sender address
+    pub sender: Option<String>,
+    /// Case-insensitive substring of the decoded subject
+    pub subject: Option<String>,
+    /// Received at or after this time
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Received before this time
+    pub until: Option<chrono::DateTime<chrono::Utc>>,
+    pub has_attachments: Option<bool>,
+    /// Words which must all appear in the subject, sender or decoded body text,
+    /// in which case the most relevant mail comes first
+    pub text: Option<String>,
+}
+
+/// Where a page of `Client::query_mail_by_recipient` results starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PageStart {
+    /// Skips this many matching messages
+    Offset(u64),
+    /// Continues after the message with this date and id.
+    /// Only valid without full-text search, whose results are ordered by relevance instead of date.
+    After { date: String, id: i64 },
+}
+
+impl Default for PageStart {
+    fn default() -> Self {
+        Self::Offset(0)
+    }
+}
+
+/// Columns selected for a `MailRecord`, in the order expected by `mail_record_from_row`
+const MAIL_RECORD_COLUMNS: &str =
+    "mail.rowid, date, sender, recipients, data, subject, seen, flagged, key";
+
+/// Condition matching mail addressed to the recipient bound to it, see `recipient_address`
+const ADDRESSED_TO: &str = "mail.rowid IN (SELECT mail_id FROM recipients WHERE address = ?)";
+
+/// Adds the recipients of mail missing from the `recipients` table to it,
+/// splitting the comma-separated `recipients` column
+const INDEX_MISSING_RECIPIENTS: &str = "WITH RECURSIVE split(mail_id, address, rest) AS (\
+        SELECT rowid, NULL, recipients || ', ' FROM mail \
+        WHERE rowid NOT IN (SELECT mail_id FROM recipients) \
+        UNION ALL \
+        SELECT mail_id, substr(rest, 1, instr(rest, ', ') - 1), substr(rest, instr(rest, ', ') + 2) \
+        FROM split WHERE rest <> ''\
+    ) \
+    INSERT INTO recipients (mail_id, address) \
+    SELECT DISTINCT mail_id, lower(trim(trim(address), '<>')) FROM split \
+    WHERE trim(trim(address), '<>') <> ''";
+
+/// SQL expression generating a message key from 128 bits of SQLite's CSPRNG
+const NEW_MAIL_KEY: &str = "lower(hex(randomblob(16)))";
+
+/// Messages decoded at once when backfilling the search index
+const BACKFILL_BATCH_SIZE: usize = 100;
+/// Number of messages deleted by a single statement
+const DELETE_BATCH_SIZE: usize = 100;
+/// Format of the `date` column, which sorts chronologically
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+/// Columns added to the `mail` table after its initial schema,
+/// which are created on existing databases when missing.
+/// Existing rows are filled with the value of the optional expression.
+const ADDED_MAIL_COLUMNS: &[(&str, &str, Option<&str>)] = &[
+    ("seen", "integer NOT NULL DEFAULT 0", None),
+    ("flagged", "integer NOT NULL DEFAULT 0", None),
+    // Decoded at replication time, so that filters can run in SQL.
+    // They are NULL for mail stored by older versions.
+    ("subject", "text", None),
+    ("body_text", "text", None),
+    ("has_attachments", "integer", None),
+    ("key", "text", Some(NEW_MAIL_KEY)),
+    // When the message is moved to the trash, set on arrival for inboxes with a lifetime in
+    // their address and by the retention job for all other mail
+    ("expires", "text", None),
+    // When the message was moved to the trash, or NULL while it is not in the trash
+    ("deleted", "text", None),
+    // When the message was last taken out of the trash, which starts its lifetime over
+    ("restored", "text", None),
+];
+
+impl MailRecord {
+    /// Checks whether the mail was sent to exactly the given inbox,
+    /// like the lookups of `Client::query_mail_by_recipient`.
+    pub fn is_addressed_to(&self, inbox: &str) -> bool {
+        let inbox = bare_address(inbox);
+        self.recipients
+            .split(", ")
+            .any(|recipient| bare_address(recipient).eq_ignore_ascii_case(inbox))
+    }
+
+    /// The id under which the message is exposed by the API: the rowid, which keeps ids ordered,
+    /// followed by the random key, which keeps them from being enumerated
+    pub fn public_id(&self) -> String {
+        format!("{}-{}", self.id, self.key)
+    }
+}
+
+/// What a ban applies to: connections from an IP address, or mail from a sender domain
+/// and its subdomains
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BanKind {
+    Ip,
+    Domain,
+}
+
+impl BanKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Ip => "ip",
+            Self::Domain => "domain",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ban {
+    pub kind: String,
+    pub value: String,
+    pub created: String,
+}
+
+/// The fields of a message which retention is decided on
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MailExpiry {
+    pub id: i64,
+    /// When the lifetime of the message started, on arrival or when it was restored
+    pub date: chrono::DateTime<chrono::Utc>,
+    pub recipients: String,
+}
+
+/// Size of the stored mail
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StorageStats {
+    pub messages: u64,
+    /// Messages in the trash, which are included in `messages`
+    pub trashed: u64,
+    /// Total size of the raw messages
+    pub mail_bytes: u64,
+    /// Size of the database file, if the backend reports it
+    pub database_bytes: Option<u64>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub mail_id: i64,
+    pub status: String,
+    pub attempts: i64,
+    pub last_error: Option<String>,
+}
+
+/// Status of an outbound webhook delivery, as stored in the `webhook_deliveries` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Retrying,
+    Delivered,
+    Failed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Retrying => "retrying",
+            Self::Delivered => "delivered",
+            Self::Failed => "failed",
+        }
+    }
 }
 
 pub struct Client {
     db: GenericClient,
 }
 
+/// URL and auth token of the database, see `connect_to`
+static DATABASE: std::sync::OnceLock<(String, Option<String>)> = std::sync::OnceLock::new();
+
+/// Makes clients connect to the database at `url`, authenticating with `auth_token` if given.
+/// Must be called before the first client is created, which otherwise uses a local database.
+pub fn connect_to(url: String, auth_token: Option<String>) -> Result<()> {
+    anyhow::ensure!(
+        DATABASE.set((url, auth_token)).is_ok(),
+        "the database was already chosen"
+    );
+    Ok(())
+}
+
+/// Set once the schema is up to date, so that it is checked by the first client only
+static SCHEMA_READY: tokio::sync::OnceCell<()> = tokio::sync::OnceCell::const_new();
+
 impl Client {
-    /// Creates a new database client.
-    /// If the LIBSQL_CLIENT_URL environment variable is not set, a local database will be used.
-    /// It's also possible to use a remote database by setting the LIBSQL_CLIENT_URL environment variable.
-    /// The `mail` table will be automatically created if it does not exist.
+    /// Creates a new client of the database chosen with `connect_to`,
+    /// or of a local database in the temporary directory if none was.
+    /// The tables are created or brought up to date by the first client of the process.
     pub async fn new() -> Result<Self> {
-        if std::env::var("LIBSQL_CLIENT_URL").is_err() {
+        let (url, auth_token) = DATABASE.get_or_init(|| {
             let mut db_path = std::env::temp_dir();
             db_path.push("edgemail.db");
             let db_path = db_path.display();
-            tracing::warn!("LIBSQL_CLIENT_URL not set, using a default local database: {db_path}");
-            std::env::set_var("LIBSQL_CLIENT_URL", format!("file://{db_path}"));
-        }
-        let db = libsql_client::new_client().await?;
-        db.batch([
-            "CREATE TABLE IF NOT EXISTS mail (date text, sender text, recipients text, data text)",
-            "CREATE INDEX IF NOT EXISTS mail_date ON mail(date)",
-            "CREATE INDEX IF NOT EXISTS mail_recipients ON mail(recipients)",
+            tracing::warn!("No database URL set, using a default local database: {db_path}");
+            (format!("file://{db_path}"), None)
+        });
+        let config = libsql_client::Config {
+            url: url::Url::parse(url).with_context(|| format!("invalid database URL: {url}"))?,
+            auth_token: auth_token.clone(),
+        };
+        let client = Self {
+            db: libsql_client::new_client_from_config(config).await?,
+        };
+        SCHEMA_READY
+            .get_or_try_init(|| client.create_schema())
+            .await?;
+        Ok(client)
+    }
+
+    /// Creates missing tables and indexes, and migrates tables created by older versions
+    async fn create_schema(&self) -> Result<()> {
+        self.db.batch([
+            // `id` is the rowid, and AUTOINCREMENT keeps the ids of purged mail from being reused,
+            // so that they can serve as cursors
+            "CREATE TABLE IF NOT EXISTS mail (id integer PRIMARY KEY AUTOINCREMENT, date text, sender text, recipients text, data text)",
+            // Superseded by the `recipients` table, since LIKE patterns cannot use it
+            "DROP INDEX IF EXISTS mail_recipients",
+            // Every recipient of every message, as a bare lowercase address
+            "CREATE TABLE IF NOT EXISTS recipients (mail_id integer, address text)",
+            "CREATE INDEX IF NOT EXISTS recipients_address ON recipients(address, mail_id)",
+            "CREATE INDEX IF NOT EXISTS recipients_mail ON recipients(mail_id)",
+            // Full-text index of the decoded columns, sharing rowids with `mail`
+            "CREATE VIRTUAL TABLE IF NOT EXISTS mail_search USING fts5(subject, sender, body_text, tokenize = 'unicode61 remove_diacritics 2')",
+            "CREATE TABLE IF NOT EXISTS webhook_deliveries (url text, mail_id integer, status text, attempts integer, last_error text, updated text)",
+            "CREATE TABLE IF NOT EXISTS inbox_claims (inbox text PRIMARY KEY, claimed text)",
+            "CREATE TABLE IF NOT EXISTS inbox_ttls (inbox text PRIMARY KEY, ttl integer, updated text)",
+            "CREATE TABLE IF NOT EXISTS bans (kind text, value text, created text, PRIMARY KEY (kind, value))",
+            // How far the retention job got, and the limits it applied
+            "CREATE TABLE IF NOT EXISTS retention_state (name text PRIMARY KEY, value text)",
         ])
         .await?;
-        Ok(Self { db })
+        self.add_missing_mail_columns().await?;
+        self.db.execute(INDEX_MISSING_RECIPIENTS).await?;
+        Ok(())
     }
 
-    /// Replicates received mail to the database
-    pub async fn replicate(&self, mail: Mail) -> Result<()> {
-        let now = chrono::offset::Utc::now()
-            .format("%Y-%m-%d %H:%M:%S%.3f")
-            .to_string();
+    /// Brings the `mail` table of databases created by older versions up to date
+    async fn add_missing_mail_columns(&self) -> Result<()> {
+        let existing: Vec<String> = self
+            .db
+            .execute("PRAGMA table_info(mail)")
+            .await?
+            .rows
+            .into_iter()
+            .filter_map(|row| row.values.into_iter().nth(1).map(value_to_string))
+            .collect();
+        for (name, definition, fill) in ADDED_MAIL_COLUMNS {
+            if existing.iter().any(|column| column == name) {
+                continue;
+            }
+            tracing::info!("Adding column {name} to the mail table");
+            if let Err(err) = self
+                .db
+                .execute(format!("ALTER TABLE mail ADD COLUMN {name} {definition}"))
+                .await
+            {
+                // Another client may have just added the same column
+                if !err.to_string().contains("duplicate column") {
+                    return Err(err);
+                }
+            }
+            if let Some(fill) = fill {
+                self.db
+                    .execute(format!(
+                        "UPDATE mail SET {name} = {fill} WHERE {name} IS NULL"
+                    ))
+                    .await?;
+            }
+        }
+        self.use_autoincrement_ids().await?;
         self.db
+            .batch([
+                "CREATE INDEX IF NOT EXISTS mail_date ON mail(date)",
+                "CREATE INDEX IF NOT EXISTS mail_expires ON mail(expires)",
+                // Superseded by `mail_deleted_date`, which also orders the trash and live mail
+                "DROP INDEX IF EXISTS mail_deleted",
+                "CREATE INDEX IF NOT EXISTS mail_deleted_date ON mail(deleted, date)",
+            ])
+            .await?;
+        Ok(())
+    }
+
+    /// Rebuilds a `mail` table created by older versions, whose plain rowids could be reused
+    /// after the newest message was purged, with an AUTOINCREMENT `id` keeping the old rowids
+    async fn use_autoincrement_ids(&self) -> Result<()> {
+        let columns = self.db.execute("PRAGMA table_info(mail)").await?.rows;
+        let mut definitions = Vec::new();
+        let mut names = Vec::new();
+        for row in columns {
+            let mut values = row.values.into_iter().skip(1);
+            let name = value_to_string(values.next().context("column missing name")?);
+            let kind = value_to_string(values.next().context("column missing type")?);
+            let not_null = value_to_i64(values.next().context("column missing notnull")?)? != 0;
+            let default = values.next().context("column missing default")?;
+            if name == "id" {
+                return Ok(());
+            }
+            let mut definition = format!("{name} {kind}");
+            if not_null {
+                definition.push_str(" NOT NULL");
+            }
+            if !matches!(default, Value::Null) {
+                definition.push_str(&format!(" DEFAULT {}", value_to_string(default)));
+            }
+            definitions.push(definition);
+            names.push(name);
+        }
+        tracing::info!("Giving the mail table AUTOINCREMENT ids");
+        let names = names.join(", ");
+        self.atomic_batch(
+            [
+                format!(
+                    "CREATE TABLE mail_autoincrement (id integer PRIMARY KEY AUTOINCREMENT, {})",
+                    definitions.join(", ")
+                ),
+                format!(
+                    "INSERT INTO mail_autoincrement (id, {names}) SELECT rowid, {names} FROM mail"
+                ),
+                "DROP TABLE mail".to_string(),
+                "ALTER TABLE mail_autoincrement RENAME TO mail".to_string(),
+            ]
+            .map(Statement::new),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Replicates received mail to the database and returns the stored record
+    pub async fn replicate(&self, mail: Mail) -> Result<MailRecord> {
+        let received = chrono::offset::Utc::now();
+        let now = timestamp(received);
+        let expires = mail
+            .to
+            .iter()
+            .filter_map(|recipient| crate::retention::address_ttl(recipient))
+            .min()
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| timestamp(received + ttl));
+        let recipients = mail.to.join(", ");
+        let mut addresses: Vec<String> = mail.to.iter().map(|to| recipient_address(to)).collect();
+        addresses.sort();
+        addresses.dedup();
+        let decoded = DecodedMail::from_raw(&mail.data);
+        // RETURNING is used instead of last_insert_rowid, which is not reported by every backend
+        let mut results = self
+            .atomic_batch([
+                Statement::with_args(
+                    format!("INSERT INTO mail (date, sender, recipients, data, subject, body_text, has_attachments, expires, key) VALUES (?, ?, ?, ?, ?, ?, ?, ?, {NEW_MAIL_KEY}) RETURNING rowid, key"),
+                    libsql_client::args!(
+                        &now,
+                        &mail.from,
+                        &recipients,
+                        &mail.data,
+                        &decoded.subject,
+                        &decoded.text,
+                        i64::from(decoded.has_attachments),
+                        expires
+                    ),
+                ),
+                Statement::with_args(
+                    "INSERT INTO mail_search (rowid, subject, sender, body_text) VALUES (last_insert_rowid(), ?, ?, ?)",
+                    libsql_client::args!(&decoded.subject, &mail.from, &decoded.text),
+                ),
+            ]
+            .into_iter()
+            .chain(addresses.iter().map(|address| {
+                // The message is the newest one, since the transaction holds the write lock
+                Statement::with_args(
+                    "INSERT INTO recipients (mail_id, address) VALUES ((SELECT MAX(rowid) FROM mail), ?)",
+                    libsql_client::args!(address),
+                )
+            })))
+            .await?;
+        let mut values = results
+            .swap_remove(0)
+            .rows
+            .into_iter()
+            .next()
+            .context("INSERT did not return a row")?
+            .values
+            .into_iter();
+        Ok(MailRecord {
+            id: value_to_i64(values.next().context("INSERT did not return a rowid")?)?,
+            date: now,
+            sender: mail.from,
+            recipients,
+            data: mail.data,
+            subject: decoded.subject,
+            seen: false,
+            flagged: false,
+            key: value_to_string(values.next().context("INSERT did not return a key")?),
+        })
+    }
+
+    /// Returns the date and recipients of up to `limit` messages in inboxes
+    /// which have not been given an expiry time yet
+    pub async fn query_mail_without_expiry(&self, limit: u32) -> Result<Vec<MailExpiry>> {
+        let result = self
+            .db
             .execute(Statement::with_args(
-                "INSERT INTO mail VALUES (?, ?, ?, ?)",
-                libsql_client::args!(now, mail.from, mail.to.join(", "), mail.data),
+                "SELECT rowid, COALESCE(restored, date), recipients FROM mail WHERE expires IS NULL AND deleted IS NULL LIMIT ?",
+                libsql_client::args!(limit),
             ))
-            .await
-            .map(|_| ())
+            .await?;
+        result
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut values = row.values.into_iter();
+                let id = value_to_i64(values.next().context("row missing id")?)?;
+                let date = value_to_string(values.next().context("row missing date")?);
+                let date = chrono::NaiveDateTime::parse_from_str(&date, TIMESTAMP_FORMAT)
+                    .with_context(|| format!("invalid date of mail {id}: {date}"))?
+                    .and_utc();
+                let recipients = value_to_string(values.next().context("row missing recipients")?);
+                Ok(MailExpiry {
+                    id,
+                    date,
+                    recipients,
+                })
+            })
+            .collect()
+    }
+
+    /// Sets when each of the given messages expires, in a single transaction
+    pub async fn set_mail_expiry(
+        &self,
+        expiry: &[(i64, chrono::DateTime<chrono::Utc>)],
+    ) -> Result<()> {
+        let stmts: Vec<Statement> = expiry
+            .iter()
+            .map(|&(id, expires)| {
+                Statement::with_args(
+                    "UPDATE mail SET expires = ? WHERE rowid = ?",
+                    libsql_client::args!(timestamp(expires), id),
+                )
+            })
+            .collect();
+        if !stmts.is_empty() {
+            self.atomic_batch(stmts).await?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the expiry time of up to `limit` messages in inboxes, so that it is worked out
+    /// again. Returns the number of messages whose expiry time was forgotten.
+    pub async fn clear_mail_expiry(&self, limit: u32) -> Result<u64> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "UPDATE mail SET expires = NULL WHERE rowid IN (SELECT rowid FROM mail WHERE expires IS NOT NULL AND deleted IS NULL LIMIT ?) RETURNING rowid",
+                libsql_client::args!(limit),
+            ))
+            .await?;
+        Ok(result.rows.len() as u64)
+    }
+
+    /// Returns the ids of up to `limit` messages whose expiry time has passed
+    pub async fn query_expired_mail(
+        &self,
+        now: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> Result<Vec<i64>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT rowid FROM mail WHERE expires < ? AND deleted IS NULL LIMIT ?",
+                libsql_client::args!(timestamp(now), limit),
+            ))
+            .await?;
+        rows_to_ids(result)
+    }
+
+    /// Returns the ids of up to `limit` messages addressed to the inbox,
+    /// other than the newest `keep` ones
+    pub async fn query_mail_beyond_count(
+        &self,
+        inbox: &str,
+        keep: u64,
+        limit: u32,
+    ) -> Result<Vec<i64>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                format!("SELECT rowid FROM mail WHERE {ADDRESSED_TO} AND deleted IS NULL ORDER BY date DESC, rowid DESC LIMIT ? OFFSET ?"),
+                libsql_client::args!(recipient_address(inbox), limit, keep as i64),
+            ))
+            .await?;
+        rows_to_ids(result)
+    }
+
+    /// Returns the ids and sizes of up to `limit` of the oldest messages, starting with
+    /// those which have been in the trash for longest, followed by the oldest mail in inboxes
+    pub async fn query_oldest_mail(&self, limit: u32) -> Result<Vec<(i64, u64)>> {
+        let mut oldest = Vec::new();
+        for query in [
+            "SELECT rowid, length(CAST(data AS BLOB)) FROM mail WHERE deleted IS NOT NULL ORDER BY deleted, date, rowid LIMIT ?",
+            "SELECT rowid, length(CAST(data AS BLOB)) FROM mail WHERE deleted IS NULL ORDER BY date, rowid LIMIT ?",
+        ] {
+            let remaining = limit - oldest.len() as u32;
+            if remaining == 0 {
+                break;
+            }
+            let result = self
+                .db
+                .execute(Statement::with_args(query, libsql_client::args!(remaining)))
+                .await?;
+            for row in result.rows {
+                let mut values = row.values.into_iter();
+                let id = value_to_i64(values.next().context("row missing id")?)?;
+                let size = value_to_i64(values.next().context("row missing size")?)?;
+                oldest.push((id, size as u64));
+            }
+        }
+        Ok(oldest)
+    }
+
+    /// Returns up to `limit` inboxes, sorted and following `after`,
+    /// to which mail with ids in `ids` was addressed
+    pub async fn query_inboxes_of_mail(
+        &self,
+        ids: std::ops::RangeInclusive<i64>,
+        after: &str,
+        limit: u32,
+    ) -> Result<Vec<String>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT DISTINCT address FROM recipients WHERE mail_id BETWEEN ? AND ? AND address > ? ORDER BY address LIMIT ?",
+                libsql_client::args!(*ids.start(), *ids.end(), after, limit),
+            ))
+            .await?;
+        Ok(result
+            .rows
+            .into_iter()
+            .filter_map(|row| row.values.into_iter().next().map(value_to_string))
+            .collect())
+    }
+
+    /// Returns a value recorded with `set_retention_state`
+    pub async fn retention_state(&self, name: &str) -> Result<Option<String>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT value FROM retention_state WHERE name = ?",
+                libsql_client::args!(name),
+            ))
+            .await?;
+        Ok(result
+            .rows
+            .into_iter()
+            .next()
+            .and_then(|row| row.values.into_iter().next())
+            .map(value_to_string))
+    }
+
+    /// Records how far the retention job got, or which limits it applied
+    pub async fn set_retention_state(&self, name: &str, value: &str) -> Result<()> {
+        self.db
+            .execute(Statement::with_args(
+                "INSERT INTO retention_state VALUES (?, ?) ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+                libsql_client::args!(name, value),
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the ids of up to `limit` messages moved to the trash before `cutoff`
+    pub async fn query_trash_before(
+        &self,
+        cutoff: chrono::DateTime<chrono::Utc>,
+        limit: u32,
+    ) -> Result<Vec<i64>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT rowid FROM mail WHERE deleted < ? LIMIT ?",
+                libsql_client::args!(timestamp(cutoff), limit),
+            ))
+            .await?;
+        rows_to_ids(result)
     }
 
-    /// Cleans up old mail
-    pub async fn delete_old_mail(&self) -> Result<()> {
-        let now = chrono::offset::Utc::now();
-        let a_week_ago = now - chrono::Duration::days(7);
-        let a_week_ago = &a_week_ago.format("%Y-%m-%d %H:%M:%S%.3f").to_string();
-        tracing::trace!("Deleting old mail from before {a_week_ago}");
+    /// Moves the given messages to the trash and returns how many of them were not there yet
+    pub async fn trash_mail_by_ids(&self, ids: &[i64]) -> Result<u64> {
+        let now = timestamp(chrono::offset::Utc::now());
+        let mut stmts = Vec::new();
+        for chunk in ids.chunks(DELETE_BATCH_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let args: Vec<Value> = std::iter::once(Value::from(now.as_str()))
+                .chain(chunk.iter().map(|&id| Value::from(id)))
+                .collect();
+            stmts.push(Statement::with_args(
+                format!("UPDATE mail SET deleted = ? WHERE rowid IN ({placeholders}) AND deleted IS NULL RETURNING rowid"),
+                &args,
+            ));
+        }
+        if stmts.is_empty() {
+            return Ok(0);
+        }
+        let results = self.atomic_batch(stmts).await?;
+        Ok(results.iter().map(|result| result.rows.len() as u64).sum())
+    }
+
+    /// Deletes the given messages for good, whether or not they are in the trash,
+    /// in a single transaction. Returns how many of them existed.
+    pub async fn purge_mail_by_ids(&self, ids: &[i64]) -> Result<u64> {
+        let mut stmts = Vec::new();
+        for chunk in ids.chunks(DELETE_BATCH_SIZE) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            let args: Vec<Value> = chunk.iter().map(|&id| Value::from(id)).collect();
+            stmts.push(Statement::with_args(
+                format!("DELETE FROM mail_search WHERE rowid IN ({placeholders})"),
+                &args,
+            ));
+            stmts.push(Statement::with_args(
+                format!("DELETE FROM recipients WHERE mail_id IN ({placeholders})"),
+                &args,
+            ));
+            stmts.push(Statement::with_args(
+                format!("DELETE FROM mail WHERE rowid IN ({placeholders}) RETURNING rowid"),
+                &args,
+            ));
+        }
+        if stmts.is_empty() {
+            return Ok(0);
+        }
+        let results = self.atomic_batch(stmts).await?;
+        // Every third result comes from deleting mail rather than index entries
+        let deleted = results
+            .iter()
+            .skip(2)
+            .step_by(3)
+            .map(|result| result.rows.len() as u64)
+            .sum();
+        Ok(deleted)
+    }
 
-        let count: i64 = i64::try_from(
-            self.db
+    /// Decodes mail stored before the derived columns existed and adds every message
+    /// missing from the full-text index to it. Returns the number of newly indexed messages.
+    pub async fn backfill_search_index(&self) -> Result<u64> {
+        self.db.execute(INDEX_MISSING_RECIPIENTS).await?;
+        loop {
+            let rows = self
+                .db
                 .execute(Statement::with_args(
-                    "SELECT COUNT(*) FROM mail WHERE date < ?",
-                    libsql_client::args!(a_week_ago),
+                    "SELECT rowid, data FROM mail WHERE subject IS NULL LIMIT ?",
+                    libsql_client::args!(BACKFILL_BATCH_SIZE),
                 ))
                 .await?
-                .rows
-                .first()
-                .context("No rows returned from a COUNT(*) query")?
-                .values
-                .first()
-                .context("No values returned from a COUNT(*) query")?,
-        )
-        .map_err(|e| anyhow::anyhow!(e))?;
-        tracing::debug!("Found {count} old mail");
+                .rows;
+            if rows.is_empty() {
+                break;
+            }
+            let mut updates = Vec::with_capacity(rows.len());
+            for row in rows {
+                let mut values = row.values.into_iter();
+                let id = value_to_i64(values.next().context("mail row missing id")?)?;
+                let data = value_to_string(values.next().context("mail row missing data")?);
+                let decoded = DecodedMail::from_raw(&data);
+                updates.push(Statement::with_args(
+                    "UPDATE mail SET subject = ?, body_text = ?, has_attachments = ? WHERE rowid = ?",
+                    libsql_client::args!(
+                        &decoded.subject,
+                        &decoded.text,
+                        i64::from(decoded.has_attachments),
+                        id
+                    ),
+                ));
+            }
+            tracing::debug!(
+                "Decoded {} messages stored by an older version",
+                updates.len()
+            );
+            self.atomic_batch(updates).await?;
+        }
+        let result = self
+            .db
+            .execute(
+                "INSERT INTO mail_search (rowid, subject, sender, body_text) \
+                 SELECT rowid, subject, sender, body_text FROM mail \
+                 WHERE rowid NOT IN (SELECT rowid FROM mail_search) RETURNING rowid",
+            )
+            .await?;
+        Ok(result.rows.len() as u64)
+    }
 
+    /// Runs the statements in a single transaction
+    async fn atomic_batch(
+        &self,
+        stmts: impl IntoIterator<Item = Statement>,
+    ) -> Result<Vec<ResultSet>> {
+        let result = self.db.batch(stmts).await;
+        if result.is_err() {
+            // The local backend stops at the failed statement, leaving the transaction open
+            self.db.execute("ROLLBACK").await.ok();
+        }
+        result
+    }
+
+    /// Records a new webhook delivery of the given mail and returns its id
+    pub async fn create_webhook_delivery(&self, url: &str, mail_id: i64) -> Result<i64> {
+        let now = timestamp(chrono::offset::Utc::now());
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "INSERT INTO webhook_deliveries VALUES (?, ?, ?, 0, NULL, ?) RETURNING rowid",
+                libsql_client::args!(url, mail_id, DeliveryStatus::Pending.as_str(), &now),
+            ))
+            .await?;
+        let id = result
+            .rows
+            .into_iter()
+            .next()
+            .and_then(|row| row.values.into_iter().next())
+            .context("INSERT did not return a rowid")?;
+        value_to_i64(id)
+    }
+
+    /// Updates the status of a webhook delivery after an attempt
+    pub async fn update_webhook_delivery(
+        &self,
+        id: i64,
+        status: DeliveryStatus,
+        attempts: u32,
+        last_error: Option<&str>,
+    ) -> Result<()> {
+        let now = timestamp(chrono::offset::Utc::now());
         self.db
             .execute(Statement::with_args(
-                "DELETE FROM mail WHERE date < ?",
-                libsql_client::args!(a_week_ago),
+                "UPDATE webhook_deliveries SET status = ?, attempts = ?, last_error = ?, updated = ? WHERE rowid = ?",
+                libsql_client::args!(status.as_str(), attempts, last_error, &now, id),
             ))
             .await
-            .ok();
-        Ok(())
+            .map(|_| ())
     }
-    pub async fn query_mail_by_recipient(&self, recipient: &str) -> Result<Vec<MailRecord>> {
-        let stmt = Statement::with_args(
-            "SELECT rowid, date, sender, recipients, data FROM mail WHERE recipients LIKE ? ORDER BY date DESC",
-            libsql_client::args!(format!("%{}%", recipient))
+
+    pub async fn query_webhook_delivery(&self, id: i64) -> Result<Option<WebhookDelivery>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT rowid, url, mail_id, status, attempts, last_error FROM webhook_deliveries WHERE rowid = ?",
+                libsql_client::args!(id),
+            ))
+            .await?;
+        result
+            .rows
+            .into_iter()
+            .next()
+            .map(Self::webhook_delivery_from_row)
+            .transpose()
+    }
+
+    /// Returns the webhook deliveries which are still pending or being retried, oldest first
+    pub async fn query_unfinished_webhook_deliveries(&self) -> Result<Vec<WebhookDelivery>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT rowid, url, mail_id, status, attempts, last_error FROM webhook_deliveries WHERE status IN (?, ?) ORDER BY rowid",
+                libsql_client::args!(
+                    DeliveryStatus::Pending.as_str(),
+                    DeliveryStatus::Retrying.as_str()
+                ),
+            ))
+            .await?;
+        result
+            .rows
+            .into_iter()
+            .map(Self::webhook_delivery_from_row)
+            .collect()
+    }
+
+    fn webhook_delivery_from_row(row: libsql_client::Row) -> Result<WebhookDelivery> {
+        let mut values = row.values.into_iter();
+        Ok(WebhookDelivery {
+            id: value_to_i64(values.next().context("delivery row missing id")?)?,
+            url: value_to_string(values.next().context("delivery row missing url")?),
+            mail_id: value_to_i64(values.next().context("delivery row missing mail id")?)?,
+            status: value_to_string(values.next().context("delivery row missing status")?),
+            attempts: value_to_i64(values.next().context("delivery row missing attempts")?)?,
+            last_error: match values.next().context("delivery row missing last error")? {
+                Value::Null => None,
+                value => Some(value_to_string(value)),
+            },
+        })
+    }
+
+    /// Updates the seen and flagged state of a message, leaving unset values unchanged.
+    /// Returns whether the message exists.
+    pub async fn update_mail_flags(
+        &self,
+        id: i64,
+        seen: Option<bool>,
+        flagged: Option<bool>,
+    ) -> Result<bool> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "UPDATE mail SET seen = COALESCE(?, seen), flagged = COALESCE(?, flagged) WHERE rowid = ? AND deleted IS NULL RETURNING rowid",
+                libsql_client::args!(seen.map(i64::from), flagged.map(i64::from), id),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Moves a single message to the trash and returns whether it existed outside of it
+    pub async fn trash_mail_by_id(&self, id: i64) -> Result<bool> {
+        Ok(self.trash_mail_by_ids(&[id]).await? == 1)
+    }
+
+    /// Takes the message with this id and key out of the trash and returns whether it was there.
+    /// Restored mail is kept as long as if it had just arrived.
+    pub async fn restore_mail_by_id(&self, id: i64, key: &str) -> Result<bool> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "UPDATE mail SET deleted = NULL, expires = NULL, restored = ? WHERE rowid = ? AND key = ? AND deleted IS NOT NULL RETURNING rowid",
+                libsql_client::args!(timestamp(chrono::offset::Utc::now()), id, key),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Takes all mail addressed to exactly this recipient out of the trash
+    /// and returns the number of restored messages, which start their lifetime over
+    pub async fn restore_mail_by_recipient(&self, recipient: &str) -> Result<u64> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                format!("UPDATE mail SET deleted = NULL, expires = NULL, restored = ? WHERE {ADDRESSED_TO} AND deleted IS NOT NULL RETURNING rowid"),
+                libsql_client::args!(timestamp(chrono::offset::Utc::now()), recipient_address(recipient)),
+            ))
+            .await?;
+        Ok(result.rows.len() as u64)
+    }
+
+    /// Returns up to `limit` inboxes after `after` with the number of messages addressed to them, sorted by address
+    pub async fn count_mail_by_inbox(&self, after: &str, limit: u32) -> Result<Vec<(String, u64)>> {
+        self.count_by_inbox("mail.deleted IS NULL", after, limit)
+            .await
+    }
+
+    /// Like `count_mail_by_inbox`, counting the messages in the trash
+    pub async fn count_trash_by_inbox(
+        &self,
+        after: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, u64)>> {
+        self.count_by_inbox("mail.deleted IS NOT NULL", after, limit)
+            .await
+    }
+
+    async fn count_by_inbox(
+        &self,
+        condition: &str,
+        after: &str,
+        limit: u32,
+    ) -> Result<Vec<(String, u64)>> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                format!(
+                    "SELECT address, COUNT(*) FROM recipients JOIN mail ON mail.rowid = recipients.mail_id \
+                    WHERE {condition} AND address > ? GROUP BY address ORDER BY address LIMIT ?"
+                ),
+                libsql_client::args!(after, limit),
+            ))
+            .await?;
+        result
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut values = row.values.into_iter();
+                let address = value_to_string(values.next().context("row missing address")?);
+                let count = value_to_i64(values.next().context("row missing count")?)?;
+                Ok((address, count as u64))
+            })
+            .collect()
+    }
+
+    pub async fn storage_stats(&self) -> Result<StorageStats> {
+        let row = self
+            .db
+            .execute("SELECT COUNT(*), COUNT(deleted), COALESCE(SUM(length(CAST(data AS BLOB))), 0) FROM mail")
+            .await?
+            .rows
+            .into_iter()
+            .next()
+            .context("No rows returned from a COUNT(*) query")?;
+        let mut values = row.values.into_iter();
+        let messages = value_to_i64(values.next().context("row missing count")?)?;
+        let trashed = value_to_i64(values.next().context("row missing trash count")?)?;
+        let mail_bytes = value_to_i64(values.next().context("row missing size")?)?;
+        // Not every backend allows pragmas, so the file size is optional
+        let database_bytes = match self
+            .db
+            .execute("SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()")
+            .await
+        {
+            Ok(result) => result
+                .rows
+                .into_iter()
+                .next()
+                .and_then(|row| row.values.into_iter().next())
+                .and_then(|value| value_to_i64(value).ok())
+                .map(|bytes| bytes as u64),
+            Err(err) => {
+                tracing::debug!("Database size is not available: {err}");
+                None
+            }
+        };
+        Ok(StorageStats {
+            messages: messages as u64,
+            trashed: trashed as u64,
+            mail_bytes: mail_bytes as u64,
+            database_bytes,
+        })
+    }
+
+    /// Bans an IP address or a sender domain. Returns false if it was already banned.
+    pub async fn add_ban(&self, kind: BanKind, value: &str) -> Result<bool> {
+        let now = timestamp(chrono::offset::Utc::now());
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "INSERT INTO bans VALUES (?, ?, ?) ON CONFLICT DO NOTHING RETURNING value",
+                libsql_client::args!(kind.as_str(), value, &now),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Lifts a ban and returns whether it existed
+    pub async fn remove_ban(&self, kind: BanKind, value: &str) -> Result<bool> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "DELETE FROM bans WHERE kind = ? AND value = ? RETURNING value",
+                libsql_client::args!(kind.as_str(), value),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    pub async fn list_bans(&self) -> Result<Vec<Ban>> {
+        let result = self
+            .db
+            .execute("SELECT kind, value, created FROM bans ORDER BY kind, value")
+            .await?;
+        result
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut values = row.values.into_iter();
+                Ok(Ban {
+                    kind: value_to_string(values.next().context("ban row missing kind")?),
+                    value: value_to_string(values.next().context("ban row missing value")?),
+                    created: value_to_string(values.next().context("ban row missing date")?),
+                })
+            })
+            .collect()
+    }
+
+    pub async fn is_ip_banned(&self, ip: std::net::IpAddr) -> Result<bool> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT 1 FROM bans WHERE kind = ? AND value = ?",
+                libsql_client::args!(BanKind::Ip.as_str(), ip.to_string()),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Checks whether the domain, or a domain it belongs to, is banned
+    pub async fn is_domain_banned(&self, domain: &str) -> Result<bool> {
+        let domain = domain.to_lowercase();
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "SELECT 1 FROM bans WHERE kind = ? AND (value = ? OR substr(?, -length(value) - 1) = '.' || value)",
+                libsql_client::args!(BanKind::Domain.as_str(), &domain, &domain),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Sets how long mail for the inbox is kept, or removes its own lifetime with None.
+    /// The mail already in the inbox gets its expiry time worked out again.
+    pub async fn set_inbox_ttl(&self, inbox: &str, ttl: Option<std::time::Duration>) -> Result<()> {
+        let inbox = bare_address(inbox).to_lowercase();
+        let forget_expiry = Statement::with_args(
+            format!("UPDATE mail SET expires = NULL WHERE {ADDRESSED_TO} AND deleted IS NULL"),
+            libsql_client::args!(recipient_address(&inbox)),
         );
+        let stmt = match ttl {
+            Some(ttl) => Statement::with_args(
+                "INSERT INTO inbox_ttls VALUES (?, ?, ?) ON CONFLICT (inbox) DO UPDATE SET ttl = excluded.ttl, updated = excluded.updated",
+                libsql_client::args!(
+                    inbox,
+                    ttl.as_secs() as i64,
+                    timestamp(chrono::offset::Utc::now())
+                ),
+            ),
+            None => Statement::with_args(
+                "DELETE FROM inbox_ttls WHERE inbox = ?",
+                libsql_client::args!(inbox),
+            ),
+        };
+        self.atomic_batch([stmt, forget_expiry]).await?;
+        Ok(())
+    }
+
+    /// Returns the inboxes with their own lifetime, set with `set_inbox_ttl`
+    pub async fn list_inbox_ttls(&self) -> Result<Vec<(String, std::time::Duration)>> {
+        let result = self.db.execute("SELECT inbox, ttl FROM inbox_ttls").await?;
+        result
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut values = row.values.into_iter();
+                let inbox = value_to_string(values.next().context("row missing inbox")?);
+                let ttl = value_to_i64(values.next().context("row missing ttl")?)?;
+                Ok((inbox, std::time::Duration::from_secs(ttl as u64)))
+            })
+            .collect()
+    }
+
+    /// Claims the inbox for whoever asked first. Returns false if it had already been claimed.
+    pub async fn claim_inbox(&self, inbox: &str) -> Result<bool> {
+        let now = timestamp(chrono::offset::Utc::now());
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                "INSERT INTO inbox_claims VALUES (?, ?) ON CONFLICT DO NOTHING RETURNING inbox",
+                libsql_client::args!(bare_address(inbox).to_lowercase(), &now),
+            ))
+            .await?;
+        Ok(!result.rows.is_empty())
+    }
+
+    /// Moves all mail addressed to exactly this recipient to the trash
+    /// and returns the number of trashed messages
+    pub async fn trash_mail_by_recipient(&self, recipient: &str) -> Result<u64> {
+        let result = self
+            .db
+            .execute(Statement::with_args(
+                format!("UPDATE mail SET deleted = ? WHERE {ADDRESSED_TO} AND deleted IS NULL RETURNING rowid"),
+                libsql_client::args!(
+                    timestamp(chrono::offset::Utc::now()),
+                    recipient_address(recipient)
+                ),
+            ))
+            .await?;
+        Ok(result.rows.len() as u64)
+    }
+
+    pub async fn query_mail_by_recipient(
+        &self,
+        recipient: &str,
+        filter: &MailFilter,
+        start: &PageStart,
+        limit: u32,
+    ) -> Result<Vec<MailRecord>> {
+        let search = filter.text.as_deref().and_then(fts_query);
+        let mut sql = format!("SELECT {MAIL_RECORD_COLUMNS} FROM mail");
+        let mut args = Vec::new();
+        if let Some(search) = &search {
+            sql.push_str(
+                " JOIN (SELECT rowid AS match_id, rank FROM mail_search WHERE mail_search MATCH ?) AS matches ON matches.match_id = mail.rowid",
+            );
+            args.push(Value::from(search.as_str()));
+        }
+        sql.push_str(&format!(" WHERE {ADDRESSED_TO} AND deleted IS NULL"));
+        args.push(Value::from(recipient_address(recipient)));
+        if let Some(seen) = filter.seen {
+            sql.push_str(" AND seen = ?");
+            args.push(Value::from(i64::from(seen)));
+        }
+        if let Some(flagged) = filter.flagged {
+            sql.push_str(" AND flagged = ?");
+            args.push(Value::from(i64::from(flagged)));
+        }
+        if let Some(sender) = &filter.sender {
+            sql.push_str(" AND sender LIKE ? ESCAPE '\\'");
+            args.push(Value::from(format!("%{}%", escape_like(sender))));
+        }
+        if let Some(subject) = &filter.subject {
+            sql.push_str(" AND subject LIKE ? ESCAPE '\\'");
+            args.push(Value::from(format!("%{}%", escape_like(subject))));
+        }
+        if let Some(since) = filter.since {
+            sql.push_str(" AND date >= ?");
+            args.push(Value::from(timestamp(since)));
+        }
+        if let Some(until) = filter.until {
+            sql.push_str(" AND date < ?");
+            args.push(Value::from(timestamp(until)));
+        }
+        if let Some(has_attachments) = filter.has_attachments {
+            sql.push_str(" AND has_attachments = ?");
+            args.push(Value::from(i64::from(has_attachments)));
+        }
+        let offset = match start {
+            PageStart::Offset(offset) => *offset,
+            PageStart::After { date, id } => {
+                anyhow::ensure!(
+                    search.is_none(),
+                    "full-text search results cannot be paged by date"
+                );
+                // Keyset pagination, which stays cheap no matter how deep the page is
+                sql.push_str(" AND (date, mail.rowid) < (?, ?)");
+                args.push(Value::from(date.as_str()));
+                args.push(Value::from(*id));
+                0
+            }
+        };
+        if search.is_some() {
+            sql.push_str(" ORDER BY matches.rank, date DESC, mail.rowid DESC");
+        } else {
+            sql.push_str(" ORDER BY date DESC, mail.rowid DESC");
+        }
+        sql.push_str(" LIMIT ? OFFSET ?");
+        args.push(Value::from(i64::from(limit)));
+        args.push(Value::from(i64::try_from(offset)?));
+        let stmt = Statement::with_args(sql, &args);
         let result = self.db.execute(stmt).await?;
         result
             .rows
@@ -104,8 +1172,8 @@ impl Client {
         timestamp: &str,
     ) -> Result<Vec<MailRecord>> {
         let stmt = Statement::with_args(
-            "SELECT rowid, date, sender, recipients, data FROM mail WHERE recipients LIKE ? AND date >= ? ORDER BY date DESC",
-            libsql_client::args!(format!("%{}%", recipient), timestamp)
+            format!("SELECT {MAIL_RECORD_COLUMNS} FROM mail WHERE {ADDRESSED_TO} AND date >= ? AND deleted IS NULL ORDER BY date DESC"),
+            libsql_client::args!(recipient_address(recipient), timestamp)
         );
         let result = self.db.execute(stmt).await?;
         result
@@ -115,9 +1183,62 @@ impl Client {
             .collect()
     }
 
+    /// Returns mail for the recipient with ids greater than `id`, newest first.
+    /// With a `limit`, only the oldest of them are returned.
+    pub async fn query_mail_after_id(
+        &self,
+        recipient: &str,
+        id: i64,
+        limit: Option<u32>,
+    ) -> Result<Vec<MailRecord>> {
+        let stmt = Statement::with_args(
+            format!("SELECT * FROM (SELECT {MAIL_RECORD_COLUMNS} FROM mail WHERE {ADDRESSED_TO} AND rowid > ? AND deleted IS NULL ORDER BY mail.rowid LIMIT ?) ORDER BY 1 DESC"),
+            libsql_client::args!(
+                recipient_address(recipient),
+                id,
+                limit.map_or(-1, i64::from)
+            )
+        );
+        let result = self.db.execute(stmt).await?;
+        result
+            .rows
+            .into_iter()
+            .map(Self::mail_record_from_row)
+            .collect()
+    }
+
+    /// Returns mail for every inbox with ids greater than `id`, oldest first
+    pub async fn query_all_mail_after_id(&self, id: i64) -> Result<Vec<MailRecord>> {
+        let stmt = Statement::with_args(
+            format!("SELECT {MAIL_RECORD_COLUMNS} FROM mail WHERE rowid > ? AND deleted IS NULL ORDER BY mail.rowid"),
+            libsql_client::args!(id),
+        );
+        let result = self.db.execute(stmt).await?;
+        result
+            .rows
+            .into_iter()
+            .map(Self::mail_record_from_row)
+            .collect()
+    }
+
+    /// Returns the id of the newest message, or 0 if there is none
+    pub async fn latest_mail_id(&self) -> Result<i64> {
+        let result = self
+            .db
+            .execute("SELECT COALESCE(MAX(rowid), 0) FROM mail")
+            .await?;
+        let value = result
+            .rows
+            .into_iter()
+            .next()
+            .and_then(|row| row.values.into_iter().next())
+            .context("MAX did not return a row")?;
+        value_to_i64(value)
+    }
+
     pub async fn query_mail_by_id(&self, id: i64) -> Result<Option<MailRecord>> {
         let stmt = Statement::with_args(
-            "SELECT rowid, date, sender, recipients, data FROM mail WHERE rowid = ? LIMIT 1",
+            format!("SELECT {MAIL_RECORD_COLUMNS} FROM mail WHERE rowid = ? AND deleted IS NULL LIMIT 1"),
             libsql_client::args!(id),
         );
         let result = self.db.execute(stmt).await?;
@@ -131,17 +1252,125 @@ impl Client {
 
     fn mail_record_from_row(row: libsql_client::Row) -> Result<MailRecord> {
         let mut values = row.values.into_iter();
+        let id = i64::try_from(values.next().context("mail row missing id")?)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        let date = value_to_string(values.next().context("mail row missing date")?);
+        let sender = value_to_string(values.next().context("mail row missing sender")?);
+        let recipients = value_to_string(values.next().context("mail row missing recipients")?);
+        let data = value_to_string(values.next().context("mail row missing data")?);
+        let subject = match values.next().context("mail row missing subject")? {
+            // Mail stored by older versions is only decoded by `backfill_search_index`
+            Value::Null => DecodedMail::from_raw(&data).subject,
+            subject => value_to_string(subject),
+        };
         Ok(MailRecord {
-            id: i64::try_from(values.next().context("mail row missing id")?)
-                .map_err(|e| anyhow::anyhow!("{:?}", e))?,
-            date: value_to_string(values.next().context("mail row missing date")?),
-            sender: value_to_string(values.next().context("mail row missing sender")?),
-            recipients: value_to_string(values.next().context("mail row missing recipients")?),
-            data: value_to_string(values.next().context("mail row missing data")?),
+            id,
+            date,
+            sender,
+            recipients,
+            data,
+            subject,
+            seen: value_to_i64(values.next().context("mail row missing seen")?)? != 0,
+            flagged: value_to_i64(values.next().context("mail row missing flagged")?)? != 0,
+            key: value_to_string(values.next().context("mail row missing key")?),
         })
     }
 }
 
+/// The address under which mail is found in the `recipients` table
+fn recipient_address(recipient: &str) -> String {
+    bare_address(recipient).to_ascii_lowercase()
+}
+
+/// Turns free text into an FTS5 query matching messages which contain all of its words,
+/// or words starting with them. Returns None if there are no words.
+fn fts_query(text: &str) -> Option<String> {
+    let terms: Vec<String> = text
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Strips whitespace and angle brackets from an address
+fn bare_address(address: &str) -> &str {
+    address.trim().trim_start_matches('<').trim_end_matches('>')
+}
+
+/// Escapes LIKE wildcards, for patterns using `ESCAPE '\'`
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+fn rows_to_ids(result: ResultSet) -> Result<Vec<i64>> {
+    result
+        .rows
+        .into_iter()
+        .map(|row| value_to_i64(row.values.into_iter().next().context("row missing id")?))
+        .collect()
+}
+
+/// Points clients at a fresh database file shared by all tests in this process.
+/// Tests using it should pick unique inbox names, since they run concurrently.
+#[cfg(test)]
+pub(crate) fn use_test_database() {
+    static INIT: std::sync::Once = std::sync::Once::new();
+    INIT.call_once(|| {
+        let mut db_path = std::env::temp_dir();
+        db_path.push(format!("edgemail-test-{}.db", std::process::id()));
+        std::fs::remove_file(&db_path).ok();
+        connect_to(format!("file://{}", db_path.display()), None).unwrap();
+    });
+}
+
+/// Returns a client of a fresh in-memory database, for tests which must not see
+/// or delete the mail of other tests
+#[cfg(test)]
+pub(crate) async fn use_private_test_database() -> Client {
+    let client = use_broken_test_database();
+    client.create_schema().await.unwrap();
+    client
+}
+
+/// Stores a message from `noreply@example.com` to `to`, as received over SMTP
+#[cfg(test)]
+pub(crate) async fn store_test_mail(db: &Client, to: &str, data: &str) -> MailRecord {
+    db.replicate(Mail {
+        from: "<noreply@example.com>".to_string(),
+        to: vec![to.to_string()],
+        data: data.to_string(),
+    })
+    .await
+    .unwrap()
+}
+
+/// Returns a client of a fresh in-memory database which cannot store mail
+#[cfg(test)]
+pub(crate) async fn use_failing_test_database() -> Client {
+    let client = use_private_test_database().await;
+    client.db.execute("DROP TABLE recipients").await.unwrap();
+    client
+}
+
+/// Returns a client of an in-memory database without any tables, on which every query fails
+#[cfg(test)]
+pub(crate) fn use_broken_test_database() -> Client {
+    Client {
+        db: GenericClient::Local(libsql_client::local::Client::in_memory().unwrap()),
+    }
+}
+
+fn value_to_i64(value: libsql_client::Value) -> Result<i64> {
+    i64::try_from(value).map_err(|e| anyhow::anyhow!("{:?}", e))
+}
+
 fn value_to_string(value: libsql_client::Value) -> String {
     match value {
         Value::Null => String::new(),
@@ -163,4 +1392,595 @@ mod tests {
         };
         assert_eq!(value_to_string(value), "Subject: Hello\r\n\r\nBody");
     }
+
+    #[test]
+    fn normalizes_recipient_addresses() {
+        assert_eq!(recipient_address(" <A@idont.date>"), "a@idont.date");
+        assert_eq!(
+            recipient_address("qa_100%@idont.date"),
+            "qa_100%@idont.date"
+        );
+    }
+
+    #[tokio::test]
+    async fn indexes_legacy_recipients() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        // Stored by an older version, with bare and mixed-case addresses
+        let legacy_id = value_to_i64(
+            db.db
+                .execute(Statement::with_args(
+                    "INSERT INTO mail (date, sender, recipients, data) VALUES (?, ?, ?, ?) RETURNING rowid",
+                    libsql_client::args!(
+                        timestamp(chrono::Utc::now()),
+                        "<old@example.com>",
+                        "Bare.Legacy@idont.date, <bracketed.legacy@idont.date>",
+                        "Subject: Legacy\r\n\r\nBody"
+                    ),
+                ))
+                .await
+                .unwrap()
+                .rows
+                .remove(0)
+                .values
+                .remove(0),
+        )
+        .unwrap();
+        db.backfill_search_index().await.unwrap();
+        for inbox in ["bare.legacy@idont.date", "<Bracketed.Legacy@idont.date>"] {
+            let found = db
+                .query_mail_by_recipient(inbox, &MailFilter::default(), &PageStart::default(), 10)
+                .await
+                .unwrap();
+            assert_eq!(found.len(), 1, "{inbox}");
+            assert_eq!(found[0].id, legacy_id);
+        }
+        assert!(db
+            .query_mail_by_recipient(
+                "legacy@idont.date",
+                &MailFilter::default(),
+                &PageStart::default(),
+                10
+            )
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Looking up an inbox uses the index rather than scanning every recipient
+        let plan = db
+            .db
+            .execute(Statement::with_args(
+                format!("EXPLAIN QUERY PLAN SELECT rowid FROM mail WHERE {ADDRESSED_TO}"),
+                libsql_client::args!("bare.legacy@idont.date"),
+            ))
+            .await
+            .unwrap();
+        let plan: Vec<String> = plan
+            .rows
+            .into_iter()
+            .map(|row| value_to_string(row.values.into_iter().last().unwrap()))
+            .collect();
+        assert!(
+            plan.iter().any(|step| step.contains("recipients_address")),
+            "{plan:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn deletes_only_the_exact_mailbox() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        for to in ["<purge_me@idont.date>", "<not_purge_me@idont.date>"] {
+            store_test_mail(&db, to, "Subject: Bye\r\n\r\nBody").await;
+        }
+        assert_eq!(
+            db.trash_mail_by_recipient("Purge_Me@idont.date")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.trash_mail_by_recipient("purge_me@idont.date")
+                .await
+                .unwrap(),
+            0
+        );
+        let remaining = db
+            .query_mail_by_recipient(
+                "not_purge_me@idont.date",
+                &MailFilter::default(),
+                &PageStart::default(),
+                100,
+            )
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(db.trash_mail_by_id(remaining[0].id).await.unwrap());
+        assert!(!db.trash_mail_by_id(remaining[0].id).await.unwrap());
+        assert!(db
+            .query_mail_by_id(remaining[0].id)
+            .await
+            .unwrap()
+            .is_none());
+
+        // Trashed mail can be restored until it is purged
+        assert_eq!(
+            db.restore_mail_by_recipient("purge_me@idont.date")
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!db.restore_mail_by_id(remaining[0].id, "").await.unwrap());
+        assert!(db
+            .restore_mail_by_id(remaining[0].id, &remaining[0].key)
+            .await
+            .unwrap());
+        assert!(!db
+            .restore_mail_by_id(remaining[0].id, &remaining[0].key)
+            .await
+            .unwrap());
+        assert!(db
+            .query_mail_by_id(remaining[0].id)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn filters_by_seen_and_flagged_state() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        let mut ids = Vec::new();
+        for subject in ["First", "Second"] {
+            let record = store_test_mail(
+                &db,
+                "<flags@idont.date>",
+                &format!("Subject: {subject}\r\n\r\nBody"),
+            )
+            .await;
+            ids.push(record.id);
+        }
+        assert!(db
+            .update_mail_flags(ids[0], Some(true), None)
+            .await
+            .unwrap());
+        assert!(db
+            .update_mail_flags(ids[1], None, Some(true))
+            .await
+            .unwrap());
+        assert!(!db.update_mail_flags(-1, Some(true), None).await.unwrap());
+
+        let unread = MailFilter {
+            seen: Some(false),
+            ..Default::default()
+        };
+        let rows = db
+            .query_mail_by_recipient("flags@idont.date", &unread, &PageStart::default(), 100)
+            .await
+            .unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].id, ids[1]);
+        assert!(rows[0].flagged);
+
+        let record = db.query_mail_by_id(ids[0]).await.unwrap().unwrap();
+        assert!(record.seen);
+        assert!(!record.flagged);
+        assert_eq!(record.key.len(), 32);
+        assert_eq!(record.public_id(), format!("{}-{}", ids[0], record.key));
+    }
+
+    #[tokio::test]
+    async fn filters_by_decoded_contents() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        let mut ids = Vec::new();
+        for (from, data) in [
+            (
+                "<alerts@bank.example>",
+                "Subject: =?utf-8?Q?Your_statement?=\r\n\r\nThe 100% balance is ready",
+            ),
+            (
+                "<friend@example.com>",
+                "Subject: Photos\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n\
+                 --b\r\nContent-Type: text/plain\r\n\r\nHoliday pictures\r\n\
+                 --b\r\nContent-Type: image/png\r\nContent-Disposition: attachment; filename=a.png\r\n\r\nPNG\r\n\
+                 --b--\r\n",
+            ),
+        ] {
+            let record = db
+                .replicate(Mail {
+                    from: from.to_string(),
+                    to: vec!["<search@idont.date>".to_string()],
+                    data: data.to_string(),
+                })
+                .await
+                .unwrap();
+            ids.push(record.id);
+        }
+        let query = |filter: MailFilter| {
+            let db = &db;
+            async move {
+                db.query_mail_by_recipient("search@idont.date", &filter, &PageStart::default(), 100)
+                    .await
+                    .unwrap()
+                    .into_iter()
+                    .map(|record| record.id)
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        let by_sender = query(MailFilter {
+            sender: Some("BANK".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_sender.await, vec![ids[0]]);
+        let by_subject = query(MailFilter {
+            subject: Some("statement".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_subject.await, vec![ids[0]]);
+        let with_attachments = query(MailFilter {
+            has_attachments: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(with_attachments.await, vec![ids[1]]);
+        let by_text = query(MailFilter {
+            text: Some("pictures holiday".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_text.await, vec![ids[1]]);
+        // Words match prefixes of indexed words, in any of the indexed columns
+        let by_prefix = query(MailFilter {
+            text: Some("STATE bank".to_string()),
+            ..Default::default()
+        });
+        assert_eq!(by_prefix.await, vec![ids[0]]);
+        // FTS5 syntax is not interpreted
+        let by_syntax = query(MailFilter {
+            text: Some("\"balance OR photos".to_string()),
+            ..Default::default()
+        });
+        assert!(by_syntax.await.is_empty());
+        let in_the_future = query(MailFilter {
+            since: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        });
+        assert!(in_the_future.await.is_empty());
+        let until_now = query(MailFilter {
+            until: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+            ..Default::default()
+        });
+        assert_eq!(until_now.await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn claims_inboxes_once() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        assert!(db.claim_inbox("<Claimed@idont.date>").await.unwrap());
+        assert!(!db.claim_inbox("claimed@idont.date").await.unwrap());
+        assert!(db.claim_inbox("unclaimed@idont.date").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn bans_addresses_and_domains() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        let ip: std::net::IpAddr = "192.0.2.39".parse().unwrap();
+        assert!(db.add_ban(BanKind::Ip, &ip.to_string()).await.unwrap());
+        assert!(!db.add_ban(BanKind::Ip, &ip.to_string()).await.unwrap());
+        assert!(db.is_ip_banned(ip).await.unwrap());
+        assert!(!db
+            .is_ip_banned("192.0.2.40".parse().unwrap())
+            .await
+            .unwrap());
+
+        assert!(db.add_ban(BanKind::Domain, "spam39.example").await.unwrap());
+        assert!(db.is_domain_banned("Spam39.Example").await.unwrap());
+        assert!(db.is_domain_banned("mx.spam39.example").await.unwrap());
+        assert!(!db.is_domain_banned("notspam39.example").await.unwrap());
+        assert!(db
+            .list_bans()
+            .await
+            .unwrap()
+            .iter()
+            .any(|ban| ban.kind == "domain" && ban.value == "spam39.example"));
+
+        assert!(db.remove_ban(BanKind::Ip, &ip.to_string()).await.unwrap());
+        assert!(!db.remove_ban(BanKind::Ip, &ip.to_string()).await.unwrap());
+        assert!(!db.is_ip_banned(ip).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn measures_mail_in_bytes() {
+        let db = use_private_test_database().await;
+        let data = "Subject: Zażółć\r\n\r\nGęślą jaźń".to_string();
+        let record = store_test_mail(&db, "<bytes@idont.date>", &data).await;
+        assert_eq!(
+            db.storage_stats().await.unwrap().mail_bytes,
+            data.len() as u64
+        );
+        assert_eq!(
+            db.query_oldest_mail(10).await.unwrap(),
+            [(record.id, data.len() as u64)]
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_mail_per_inbox() {
+        let db = use_private_test_database().await;
+        for to in [
+            vec!["<Counted_a@idont.date>".to_string()],
+            vec![
+                "<counted_a@idont.date>".to_string(),
+                "<counted_b@idont.date>".to_string(),
+            ],
+        ] {
+            db.replicate(Mail {
+                from: "<noreply@example.com>".to_string(),
+                to,
+                data: "Subject: Count\r\n\r\nBody".to_string(),
+            })
+            .await
+            .unwrap();
+        }
+        let counts = db.count_mail_by_inbox("", 10).await.unwrap();
+        assert_eq!(
+            counts,
+            [
+                ("counted_a@idont.date".to_string(), 2),
+                ("counted_b@idont.date".to_string(), 1)
+            ]
+        );
+        let page = db.count_mail_by_inbox("counted_a@idont.date", 1).await;
+        assert_eq!(page.unwrap(), [("counted_b@idont.date".to_string(), 1)]);
+        assert!(db.count_trash_by_inbox("", 10).await.unwrap().is_empty());
+
+        let stats = db.storage_stats().await.unwrap();
+        assert!(stats.messages >= 2);
+        assert!(stats.mail_bytes > 0);
+    }
+
+    #[tokio::test]
+    async fn selects_and_deletes_oldest_mail() {
+        let db = use_private_test_database().await;
+        assert!(db.query_oldest_mail(10).await.unwrap().is_empty());
+
+        let mut ids = Vec::new();
+        for subject in ["Doomed", "Also doomed"] {
+            let record = store_test_mail(
+                &db,
+                "<delete_by_ids@idont.date>",
+                &format!("Subject: {subject}\r\n\r\nBody"),
+            )
+            .await;
+            ids.push(record.id);
+        }
+        let oldest = db.query_oldest_mail(10).await.unwrap();
+        assert_eq!(oldest.iter().map(|(id, _)| *id).collect::<Vec<_>>(), ids);
+        assert!(oldest.iter().all(|(_, size)| *size > 0));
+        assert_eq!(db.query_oldest_mail(1).await.unwrap().len(), 1);
+        assert_eq!(
+            db.query_mail_beyond_count("Delete_By_Ids@idont.date", 1, 10)
+                .await
+                .unwrap(),
+            vec![ids[0]]
+        );
+        assert!(db
+            .query_mail_beyond_count("Delete_By_Ids@idont.date", 0, 0)
+            .await
+            .unwrap()
+            .is_empty());
+        ids.push(i64::MAX);
+        assert_eq!(db.trash_mail_by_ids(&ids).await.unwrap(), 2);
+        assert_eq!(db.trash_mail_by_ids(&ids).await.unwrap(), 0);
+        assert!(db
+            .query_mail_beyond_count("delete_by_ids@idont.date", 0, 10)
+            .await
+            .unwrap()
+            .is_empty());
+        let later = chrono::Utc::now() + chrono::Duration::seconds(1);
+        let trash = db.query_trash_before(later, u32::MAX).await.unwrap();
+        assert!(ids[..2].iter().all(|id| trash.contains(id)));
+        let earlier = chrono::Utc::now() - chrono::Duration::days(1);
+        let trash = db.query_trash_before(earlier, u32::MAX).await.unwrap();
+        assert!(!trash.contains(&ids[0]));
+        assert_eq!(db.purge_mail_by_ids(&ids).await.unwrap(), 2);
+        assert_eq!(db.purge_mail_by_ids(&ids).await.unwrap(), 0);
+        assert_eq!(db.purge_mail_by_ids(&[]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn never_reuses_the_id_of_purged_mail() {
+        let db = use_private_test_database().await;
+        let data = "Subject: Reused\r\n\r\nBody";
+        let purged = store_test_mail(&db, "<reused@idont.date>", data).await;
+        db.trash_mail_by_id(purged.id).await.unwrap();
+        assert_eq!(db.purge_mail_by_ids(&[purged.id]).await.unwrap(), 1);
+        let next = store_test_mail(&db, "<reused@idont.date>", data).await;
+        assert!(next.id > purged.id, "{} reused {}", next.id, purged.id);
+    }
+
+    #[tokio::test]
+    async fn migrates_mail_to_autoincrement_ids() {
+        let db = use_broken_test_database();
+        db.db
+            .batch([
+                "CREATE TABLE mail (date text, sender text, recipients text, data text, seen integer NOT NULL DEFAULT 0)",
+                "INSERT INTO mail (rowid, date, sender, recipients, data) VALUES (7, '2026-01-01 00:00:00.000', '<old@example.com>', '<migrated@idont.date>', 'Subject: Old\r\n\r\nBody')",
+            ])
+            .await
+            .unwrap();
+        db.create_schema().await.unwrap();
+        db.create_schema().await.unwrap();
+
+        let old = db.query_mail_by_id(7).await.unwrap().unwrap();
+        assert_eq!((old.subject.as_str(), old.seen), ("Old", false));
+        assert_eq!(
+            db.query_mail_by_recipient(
+                "migrated@idont.date",
+                &MailFilter::default(),
+                &PageStart::default(),
+                10
+            )
+            .await
+            .unwrap()
+            .len(),
+            1
+        );
+        db.trash_mail_by_id(7).await.unwrap();
+        db.purge_mail_by_ids(&[7]).await.unwrap();
+        let new = store_test_mail(&db, "<migrated@idont.date>", "Subject: New\r\n\r\nBody").await;
+        assert_eq!(new.id, 8);
+    }
+
+    #[tokio::test]
+    async fn stores_inbox_ttls() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        let ttl = |days: u64| std::time::Duration::from_secs(days * 24 * 3600);
+        async fn stored(db: &Client) -> Option<std::time::Duration> {
+            db.list_inbox_ttls()
+                .await
+                .unwrap()
+                .into_iter()
+                .find(|(inbox, _)| inbox == "qa_ttl@idont.date")
+                .map(|(_, ttl)| ttl)
+        }
+        db.set_inbox_ttl("<QA_ttl@idont.date>", Some(ttl(14)))
+            .await
+            .unwrap();
+        db.set_inbox_ttl("qa_ttl@idont.date", Some(ttl(21)))
+            .await
+            .unwrap();
+        assert_eq!(stored(&db).await, Some(ttl(21)));
+        db.set_inbox_ttl("qa_ttl@idont.date", None).await.unwrap();
+        assert_eq!(stored(&db).await, None);
+    }
+
+    #[tokio::test]
+    async fn pages_by_date_and_id() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let record =
+                store_test_mail(&db, "<paged@idont.date>", "Subject: Page\r\n\r\nBody").await;
+            ids.push(record.id);
+        }
+        ids.reverse();
+        let filter = MailFilter::default();
+
+        let mut start = PageStart::default();
+        let mut seen = Vec::new();
+        loop {
+            let page = db
+                .query_mail_by_recipient("paged@idont.date", &filter, &start, 2)
+                .await
+                .unwrap();
+            let Some(last) = page.last() else { break };
+            start = PageStart::After {
+                date: last.date.clone(),
+                id: last.id,
+            };
+            seen.extend(page.iter().map(|record| record.id));
+        }
+        assert_eq!(seen, ids);
+
+        let skipped = db
+            .query_mail_by_recipient("paged@idont.date", &filter, &PageStart::Offset(3), 10)
+            .await
+            .unwrap();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[0].id, ids[3]);
+    }
+
+    #[tokio::test]
+    async fn backfills_and_ranks_search_results() {
+        use_test_database();
+        let db = Client::new().await.unwrap();
+        // Stored the way older versions did, without decoded columns or an index entry
+        let legacy_id = value_to_i64(
+            db.db
+                .execute(Statement::with_args(
+                    "INSERT INTO mail (date, sender, recipients, data) VALUES (?, ?, ?, ?) RETURNING rowid",
+                    libsql_client::args!(
+                        timestamp(chrono::Utc::now()),
+                        "<old@example.com>",
+                        "<ranked@idont.date>",
+                        "Subject: Legacy\r\n\r\nA single mention of kumquats"
+                    ),
+                ))
+                .await
+                .unwrap()
+                .rows
+                .remove(0)
+                .values
+                .remove(0),
+        )
+        .unwrap();
+        let recent = db
+            .replicate(Mail {
+                from: "<new@example.com>".to_string(),
+                to: vec!["<ranked@idont.date>".to_string()],
+                data: "Subject: Kumquats\r\n\r\nKumquats, kumquats and more kumquats".to_string(),
+            })
+            .await
+            .unwrap();
+        let search = MailFilter {
+            text: Some("kumquats".to_string()),
+            ..Default::default()
+        };
+        let ids = |rows: Vec<MailRecord>| rows.into_iter().map(|row| row.id).collect::<Vec<_>>();
+
+        let before = db
+            .query_mail_by_recipient("ranked@idont.date", &search, &PageStart::default(), 100)
+            .await
+            .unwrap();
+        assert_eq!(ids(before), vec![recent.id]);
+
+        assert!(db.backfill_search_index().await.unwrap() >= 1);
+        assert_eq!(db.backfill_search_index().await.unwrap(), 0);
+        let after = db
+            .query_mail_by_recipient("ranked@idont.date", &search, &PageStart::default(), 100)
+            .await
+            .unwrap();
+        assert_eq!(ids(after), vec![recent.id, legacy_id]);
+
+        // Purging mail removes it from the index as well
+        assert_eq!(db.purge_mail_by_ids(&[legacy_id]).await.unwrap(), 1);
+        let indexed = db
+            .db
+            .execute(Statement::with_args(
+                "SELECT COUNT(*) FROM mail_search WHERE rowid = ?",
+                libsql_client::args!(legacy_id),
+            ))
+            .await
+            .unwrap()
+            .rows
+            .remove(0)
+            .values
+            .remove(0);
+        assert_eq!(value_to_i64(indexed).unwrap(), 0);
+    }
+
+    #[test]
+    fn matches_exact_recipients_case_insensitively() {
+        let record = MailRecord {
+            id: 1,
+            date: "2026-05-18 10:00:00.000".to_string(),
+            sender: "<noreply@example.com>".to_string(),
+            recipients: "<a@idont.date>, <b@idont.date>".to_string(),
+            data: String::new(),
+            subject: String::new(),
+            seen: false,
+            flagged: false,
+            key: String::new(),
+        };
+        assert!(record.is_addressed_to("B@idont.date"));
+        assert!(!record.is_addressed_to("c@idont.date"));
+        // Other addresses merely containing the inbox do not count
+        assert!(!record.is_addressed_to("a@idont.dat"));
+        assert!(!record.is_addressed_to("@idont.date"));
+    }
 }
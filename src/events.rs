@@ -0,0 +1,36 @@
+use crate::database::MailRecord;
+use tokio::sync::broadcast;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+/// In-process channel announcing mail that has just been stored in the database.
+/// The SMTP server publishes every replicated message, and API requests
+/// waiting for new mail subscribe to it instead of re-querying the database.
+#[derive(Clone, Debug)]
+pub struct MailEvents {
+    sender: broadcast::Sender<MailRecord>,
+}
+
+impl MailEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Announces a freshly stored message to all current subscribers
+    pub fn publish(&self, record: MailRecord) {
+        // An error only means that nobody is listening right now
+        self.sender.send(record).ok();
+    }
+
+    /// Subscribes to messages published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<MailRecord> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for MailEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,3 +1,14 @@
 pub mod api;
+pub mod assets;
+pub mod auth;
+pub mod config;
 pub mod database;
+pub mod events;
+pub mod message;
+pub mod openapi;
+pub mod pattern;
+pub mod ratelimit;
+pub mod retention;
+pub mod shutdown;
 pub mod smtp;
+pub mod webhooks;
@@ -1,96 +1,328 @@
 use anyhow::{Context, Result};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::watch;
 
+use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
 
-use edgemail::{api, smtp};
+use edgemail::{
+    api,
+    config::{self, Layers, RetentionRule, Settings},
+    events::MailEvents,
+    retention,
+    retention::RetentionPolicy,
+    shutdown::{Shutdown, ShutdownSignal},
+    smtp, webhooks,
+};
 
 struct Args {
-    smtp_addr: String,
-    domain: String,
-    api_port: Option<u16>,
+    config_path: Option<PathBuf>,
+    /// Settings given as flags, along with the flag
+    overrides: Vec<(String, &'static str, String)>,
+    cors_origins: Vec<String>,
+    webhooks: Vec<String>,
+    rules: Vec<RetentionRule>,
+    /// Environment variables as they were at startup, which settings and secrets are read from
+    /// instead of the live environment
+    env: HashMap<String, String>,
+    backfill_search: bool,
+    print_config: bool,
 }
 
 impl Args {
     fn parse() -> Result<Option<Self>> {
-        let mut smtp_addr = None;
-        let mut domain = None;
-        let mut api_port = None;
+        let mut config_path = env::var_os(config::CONFIG_ENV).map(PathBuf::from);
+        let mut overrides = Vec::new();
+        let mut positional = ["smtp.addr", "smtp.domain"].into_iter();
+        let mut cors_origins = Vec::new();
+        let mut webhooks = Vec::new();
+        let mut rules = Vec::new();
+        let mut backfill_search = false;
+        let mut print_config = false;
         let mut args = env::args().skip(1);
 
         while let Some(arg) = args.next() {
-            match arg.as_str() {
-                "-h" | "--help" => {
-                    print_usage();
-                    return Ok(None);
-                }
-                "--api-port" => {
-                    let value = args.next().context("--api-port requires a port number")?;
-                    api_port = Some(value.parse().context("invalid value for --api-port")?);
+            if arg == "-h" || arg == "--help" {
+                print_usage();
+                return Ok(None);
+            }
+            if arg == "--backfill-search" {
+                backfill_search = true;
+            } else if arg == "--print-config" {
+                print_config = true;
+            } else if arg == "--require-tokens" {
+                overrides.push((arg, "api.require_tokens", "true".to_string()));
+            } else if arg == "--admin-api" {
+                overrides.push((arg, "api.admin", "true".to_string()));
+            } else if let Some(value) = flag_value("--config", &arg, &mut args)? {
+                config_path = Some(PathBuf::from(value));
+            } else if let Some(value) = flag_value("--api-port", &arg, &mut args)? {
+                overrides.push(("--api-port".to_string(), "api.port", value));
+            } else if let Some(value) = flag_value("--ip-rate-limit", &arg, &mut args)? {
+                overrides.push(("--ip-rate-limit".to_string(), "api.ip_rate_limit", value));
+            } else if let Some(value) = flag_value("--inbox-rate-limit", &arg, &mut args)? {
+                overrides.push((
+                    "--inbox-rate-limit".to_string(),
+                    "api.inbox_rate_limit",
+                    value,
+                ));
+            } else if let Some(value) = flag_value("--cors-origin", &arg, &mut args)? {
+                cors_origins.push(value);
+            } else if let Some(value) = flag_value("--webhook", &arg, &mut args)? {
+                webhooks.push(value);
+            } else if let Some(value) = flag_value("--max-age", &arg, &mut args)? {
+                match RetentionRule::max_age(&value) {
+                    Some(rule) => rules.push(rule),
+                    None => overrides.push(("--max-age".to_string(), "retention.max_age", value)),
                 }
-                value if value.starts_with("--api-port=") => {
-                    let (_, port) = value.split_once('=').context("invalid --api-port format")?;
-                    api_port = Some(port.parse().context("invalid value for --api-port")?);
+            } else if let Some(value) = flag_value("--max-messages", &arg, &mut args)? {
+                match RetentionRule::max_messages(&value)
+                    .context("invalid value for --max-messages")?
+                {
+                    Some(rule) => rules.push(rule),
+                    None => overrides.push((
+                        "--max-messages".to_string(),
+                        "retention.max_messages",
+                        value,
+                    )),
                 }
-                _ if smtp_addr.is_none() => smtp_addr = Some(arg),
-                _ if domain.is_none() => domain = Some(arg),
-                _ => anyhow::bail!("unexpected argument: {arg}"),
+            } else if let Some(value) = flag_value("--max-storage", &arg, &mut args)? {
+                overrides.push(("--max-storage".to_string(), "retention.max_storage", value));
+            } else if let Some(value) = flag_value("--trash-period", &arg, &mut args)? {
+                overrides.push((
+                    "--trash-period".to_string(),
+                    "retention.trash_period",
+                    value,
+                ));
+            } else if let Some(value) = flag_value("--cleanup-interval", &arg, &mut args)? {
+                overrides.push((
+                    "--cleanup-interval".to_string(),
+                    "retention.cleanup_interval",
+                    value,
+                ));
+            } else if let Some(value) = flag_value("--max-message-size", &arg, &mut args)? {
+                overrides.push((
+                    "--max-message-size".to_string(),
+                    "smtp.max_message_size",
+                    value,
+                ));
+            } else if let Some(value) = flag_value("--shutdown-timeout", &arg, &mut args)? {
+                overrides.push(("--shutdown-timeout".to_string(), "shutdown_timeout", value));
+            } else if arg.starts_with("--") {
+                anyhow::bail!("unknown option: {arg}");
+            } else if let Some(key) = positional.next() {
+                overrides.push((key.to_string(), key, arg));
+            } else {
+                anyhow::bail!("unexpected argument: {arg}");
             }
         }
 
         Ok(Some(Self {
-            smtp_addr: smtp_addr.unwrap_or_else(|| "0.0.0.0:2525".to_string()),
-            domain: domain.unwrap_or_else(|| "smtp.idont.date".to_string()),
-            api_port,
+            config_path,
+            overrides,
+            cors_origins,
+            webhooks,
+            rules,
+            env: env::vars().collect(),
+            backfill_search,
+            print_config,
         }))
     }
+
+    /// Reads the config file, then environment variables and finally the command line flags,
+    /// each overriding the settings before them
+    fn settings(&self) -> Result<Settings> {
+        let mut layers = Layers::new();
+        if let Some(path) = &self.config_path {
+            layers.file(path)?;
+        }
+        layers.env(|name| self.env.get(name).cloned())?;
+        for (flag, key, value) in &self.overrides {
+            layers
+                .set(key, value)
+                .with_context(|| format!("invalid value for {flag}"))?;
+        }
+        if !self.cors_origins.is_empty() {
+            layers.set_list("api.cors_origins", self.cors_origins.clone());
+        }
+        if !self.webhooks.is_empty() {
+            layers.set_list("webhooks", self.webhooks.clone());
+        }
+        layers.prepend_rules(self.rules.clone())?;
+        layers.settings()
+    }
+}
+
+/// Returns the settings of the inbox API along with its secrets, or None if it is disabled
+fn api_config(settings: &Settings, env: &HashMap<String, String>) -> Result<Option<api::Config>> {
+    let token_secret = if settings.api.require_tokens {
+        Some(
+            env.get("EDGEMAIL_TOKEN_SECRET")
+                .cloned()
+                .context("EDGEMAIL_TOKEN_SECRET must be set when tokens are required")?,
+        )
+    } else {
+        None
+    };
+    let admin_token = if settings.api.admin {
+        Some(
+            env.get("EDGEMAIL_ADMIN_TOKEN")
+                .cloned()
+                .context("EDGEMAIL_ADMIN_TOKEN must be set when the admin API is enabled")?,
+        )
+    } else {
+        None
+    };
+    settings.api_config(token_secret, admin_token)
+}
+
+/// Channels handing reloaded settings to the running services
+struct Reloads {
+    smtp: watch::Sender<smtp::Config>,
+    api: Option<watch::Sender<api::Config>>,
+    retention: watch::Sender<RetentionPolicy>,
+    shutdown_timeout: watch::Sender<Duration>,
+}
+
+impl Reloads {
+    /// Reads the settings again and applies them, unless any of them is invalid.
+    /// Settings which need a restart are compared with those the server started with.
+    fn reload(&self, args: &Args, started: &Settings) -> Result<()> {
+        let settings = args.settings()?;
+        let smtp = settings.smtp_config()?;
+        let retention = settings.retention_policy()?;
+        let api = api_config(&settings, &args.env)?;
+        let shutdown_timeout = settings.shutdown_timeout()?;
+        for key in started.restart_required(&settings) {
+            tracing::warn!("{key} changed, which only takes effect after a restart");
+        }
+        self.smtp.send_replace(smtp);
+        self.retention.send_replace(retention);
+        self.shutdown_timeout.send_replace(shutdown_timeout);
+        if let (Some(sender), Some(api)) = (&self.api, api) {
+            sender.send_replace(api);
+        }
+        Ok(())
+    }
+}
+
+/// Returns the value of `arg` if it is the given flag,
+/// passed either as `--flag VALUE` or as `--flag=VALUE`
+fn flag_value(
+    flag: &str,
+    arg: &str,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<Option<String>> {
+    if arg == flag {
+        return args
+            .next()
+            .map(Some)
+            .with_context(|| format!("{flag} requires a value"));
+    }
+    Ok(arg
+        .strip_prefix(flag)
+        .and_then(|rest| rest.strip_prefix('='))
+        .map(ToOwned::to_owned))
 }
 
 fn print_usage() {
     println!(
-        "Usage: edgemail [SMTP_ADDR] [DOMAIN] [--api-port PORT]\n\
+        "Usage: edgemail [SMTP_ADDR] [DOMAIN] [OPTIONS]\n\
          \n\
          Arguments:\n\
            SMTP_ADDR        SMTP bind address (default: 0.0.0.0:2525)\n\
            DOMAIN           SMTP domain name (default: smtp.idont.date)\n\
          \n\
          Options:\n\
+           --config PATH    Read settings from the TOML file at PATH (default: $EDGEMAIL_CONFIG), which environment variables and flags override\n\
+           --print-config   Print the effective settings as a config file, then exit\n\
+           --max-message-size SIZE  Refuse messages larger than SIZE, e.g. 25MB (default: 10MB)\n\
            --api-port PORT  Enable the inbox HTTP API on the given port\n\
+           --ip-rate-limit N/PERIOD  API requests allowed per client IP, e.g. 60/min (default: 60/min)\n\
+           --inbox-rate-limit N/PERIOD  API requests allowed per inbox, e.g. 2/s (default: 120/min)\n\
+           --cors-origin ORIGIN  Allow browsers on ORIGIN (e.g. https://sorry.idont.date, or *) to call the API; repeatable\n\
+           --webhook [PATTERN=]URL  POST accepted mail for inboxes matching PATTERN (default: all) to URL; repeatable, signed with EDGEMAIL_WEBHOOK_SECRET\n\
+           --max-age [PATTERN=]DURATION  Move mail for inboxes matching PATTERN (default: all) to the trash once it is older than DURATION, e.g. 12h or 30d (default: 7d); repeatable\n\
+           --max-messages [PATTERN=]N  Keep only the newest N messages of each inbox matching PATTERN (default: all); repeatable\n\
+           --max-storage SIZE  Purge the trash, then the oldest mail, once stored messages take up more than SIZE, e.g. 500MB\n\
+           --trash-period DURATION  How long deleted mail can be restored before it is purged (default: 7d)\n\
+           --cleanup-interval DURATION  How often old mail is deleted (default: 1h)\n\
+           --shutdown-timeout DURATION  Time sessions and requests in progress get to finish on SIGINT or SIGTERM (default: 10s)\n\
+           --require-tokens  Require a bearer token to read an inbox through the API; tokens are derived from EDGEMAIL_TOKEN_SECRET and handed out by POST /inbox/claim\n\
+           --admin-api      Serve the operator API under /admin, authenticated with EDGEMAIL_ADMIN_TOKEN as a bearer token\n\
+           --backfill-search  Add mail stored by older versions to the search index, then exit\n\
            -h, --help       Print help"
     );
 }
 
-/// A helper function for cleaning up old mail from the database
-fn periodically_clean_db(period: tokio::time::Duration) {
-    std::thread::spawn(move || -> Result<()> {
-        tokio::runtime::Builder::new_current_thread()
-            .enable_time()
-            .enable_io()
-            .build()
-            .context("failed to build async runtime")?
-            .block_on(async move {
-                let local = tokio::task::LocalSet::new();
-                local.spawn_local(async move {
-                    let db = match edgemail::database::Client::new().await {
-                        Ok(db) => db,
-                        Err(e) => {
-                            tracing::error!("Failed to connect to database: {}", e);
-                            return;
-                        }
-                    };
-                    let mut interval = tokio::time::interval(period);
-                    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-                    loop {
-                        interval.tick().await;
-                        if let Err(e) = db.delete_old_mail().await {
-                            tracing::error!("Failed to delete old mail: {}", e);
-                        }
+/// Applies the retention policy to the database every `policy.interval`,
+/// starting over with the new policy whenever it changes, until shutdown starts
+async fn periodically_clean_db(
+    mut policy: watch::Receiver<RetentionPolicy>,
+    mut shutdown: ShutdownSignal,
+) {
+    let db = match edgemail::database::Client::new().await {
+        Ok(db) => db,
+        Err(e) => {
+            tracing::error!("Failed to connect to database: {}", e);
+            return;
+        }
+    };
+    let mut start = tokio::time::Instant::now();
+    loop {
+        let current = policy.borrow_and_update().clone();
+        let mut interval = tokio::time::interval_at(start, current.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = retention::enforce(&db, &current).await {
+                        tracing::error!("Failed to delete old mail: {:#}", e);
                     }
-                });
-                local.await;
-            });
-        Ok(())
-    });
+                }
+                changed = policy.changed() => {
+                    if changed.is_err() {
+                        return;
+                    }
+                    break;
+                }
+                _ = shutdown.started() => return,
+            }
+        }
+        start = tokio::time::Instant::now() + current.interval;
+    }
+}
+
+/// Reloads the settings on every SIGHUP, keeping the current ones if the new ones are invalid
+async fn reload_on_hangup(mut hangups: Signal, args: Args, started: Settings, reloads: Reloads) {
+    while hangups.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, reloading settings");
+        match reloads.reload(&args, &started) {
+            Ok(()) => tracing::info!("Settings reloaded"),
+            Err(e) => tracing::error!("Failed to reload settings, keeping the current ones: {e:#}"),
+        }
+    }
+}
+
+/// Starts shutting down on the first SIGINT or SIGTERM
+async fn shut_down_on_signal(
+    mut interrupts: Signal,
+    mut terminations: Signal,
+    timeout: watch::Receiver<Duration>,
+    shutdown: Shutdown,
+) {
+    let name = tokio::select! {
+        _ = interrupts.recv() => "SIGINT",
+        _ = terminations.recv() => "SIGTERM",
+    };
+    let timeout = *timeout.borrow();
+    tracing::info!("Received {name}, shutting down within {timeout:?}");
+    shutdown.start(tokio::time::Instant::now() + timeout);
+    // Keeps the signal handlers registered, so that a second signal does not kill the server
+    std::future::pending::<()>().await;
 }
 
 #[tokio::main]
@@ -100,34 +332,137 @@ async fn main() -> Result<()> {
     let Some(args) = Args::parse()? else {
         return Ok(());
     };
-    let domain = args.domain.clone();
+    let settings = args.settings()?;
+    if args.print_config {
+        print!("{}", settings.to_toml()?);
+        return Ok(());
+    }
+    if let Some(url) = &settings.database_url {
+        let auth_token = args.env.get("LIBSQL_CLIENT_TOKEN").cloned();
+        edgemail::database::connect_to(url.clone(), auth_token)?;
+    }
+    if args.backfill_search {
+        let db = edgemail::database::Client::new().await?;
+        let indexed = db.backfill_search_index().await?;
+        tracing::info!("Added {indexed} messages to the search index");
+        return Ok(());
+    }
+    let api_config = api_config(&settings, &args.env)?;
+    let retention = settings.retention_policy()?;
+    let webhooks = settings.webhooks()?;
+    let smtp_config = settings.smtp_config()?;
+    let shutdown_timeout = settings.shutdown_timeout()?;
+
+    tracing::info!("edgemail server for {} started", smtp_config.domain);
 
-    tracing::info!("edgemail server for {domain} started");
+    let listener = TcpListener::bind(&settings.smtp.addr).await?;
+    tracing::info!("Listening on: {}", settings.smtp.addr);
 
-    let listener = TcpListener::bind(&args.smtp_addr).await?;
-    tracing::info!("Listening on: {}", args.smtp_addr);
+    // Database clients are not Send, so tasks using them run on a LocalSet of the main runtime
+    let local = tokio::task::LocalSet::new();
+
+    // Every service stops taking new work once shutdown starts
+    let shutdown = Shutdown::new();
+    let mut shutdown_signal = shutdown.subscribe();
 
     // Task for deleting old mail
-    periodically_clean_db(tokio::time::Duration::from_secs(3600));
+    let (retention, retention_updates) = watch::channel(retention);
+    let cleanup = local.spawn_local(periodically_clean_db(
+        retention_updates,
+        shutdown.subscribe(),
+    ));
+
+    // Channel used to wake up API requests waiting for new mail
+    let events = MailEvents::new();
 
-    if let Some(api_port) = args.api_port {
-        api::spawn(api_port);
+    let mut api_thread = None;
+    let api = api_config.map(|api_config| {
+        let (sender, updates) = watch::channel(api_config);
+        api_thread = Some(api::spawn(updates, events.clone(), shutdown.subscribe()));
+        sender
+    });
+
+    let mut webhooks_thread = None;
+    if !webhooks.is_empty() {
+        let secret = args
+            .env
+            .get("EDGEMAIL_WEBHOOK_SECRET")
+            .cloned()
+            .context("EDGEMAIL_WEBHOOK_SECRET must be set when webhooks are configured")?;
+        webhooks_thread = Some(webhooks::spawn(
+            webhooks,
+            secret,
+            &events,
+            shutdown.subscribe(),
+        ));
     }
 
-    // Main loop: accept connections and spawn a task to handle them
-    loop {
-        let (stream, addr) = listener.accept().await?;
-        tracing::info!("Accepted a connection from {}", addr);
-        let connection_domain = domain.clone();
-
-        tokio::task::LocalSet::new()
-            .run_until(async move {
-                let smtp = smtp::Server::new(&connection_domain, stream).await?;
-                tokio::time::timeout(std::time::Duration::from_secs(300), smtp.serve())
+    // Sessions use the settings current when they start
+    let (smtp, smtp_updates) = watch::channel(smtp_config);
+    let (shutdown_timeout, shutdown_timeout_updates) = watch::channel(shutdown_timeout);
+    let reloads = Reloads {
+        smtp,
+        api,
+        retention,
+        shutdown_timeout,
+    };
+    // Listening for signals right away keeps them from terminating the server
+    let hangups = signal(SignalKind::hangup())?;
+    let interrupts = signal(SignalKind::interrupt())?;
+    let terminations = signal(SignalKind::terminate())?;
+    local.spawn_local(reload_on_hangup(hangups, args, settings, reloads));
+    local.spawn_local(shut_down_on_signal(
+        interrupts,
+        terminations,
+        shutdown_timeout_updates,
+        shutdown,
+    ));
+
+    // Main loop: accept connections and handle them one at a time, until shutdown starts.
+    // A session in progress at that point finishes its mail transaction first.
+    local
+        .run_until(async move {
+            let deadline = loop {
+                let (stream, addr) = tokio::select! {
+                    // Checked first, so that no connection is accepted after shutdown starts
+                    biased;
+                    deadline = shutdown_signal.started() => break deadline,
+                    accepted = listener.accept() => accepted?,
+                };
+                tracing::info!("Accepted a connection from {}", addr);
+                let config = smtp_updates.borrow().clone();
+                let connection = async {
+                    let smtp = smtp::Server::new(&config, stream, events.clone()).await?;
+                    tokio::time::timeout(
+                        config.session_timeout,
+                        smtp.serve(shutdown_signal.clone()),
+                    )
                     .await
-                    .context("connection timed out")
-            })
-            .await
-            .ok();
-    }
+                    .context("connection timed out")?
+                };
+                if let Err(e) = connection.await {
+                    tracing::debug!("Connection from {addr} failed: {e:#}");
+                }
+            };
+            drop(listener);
+            tracing::info!("Stopped accepting SMTP connections");
+            if tokio::time::timeout_at(deadline, cleanup).await.is_err() {
+                tracing::warn!("Mail cleanup did not finish before shutdown");
+            }
+            if let Some(thread) = api_thread {
+                tokio::task::spawn_blocking(move || thread.join())
+                    .await?
+                    .map_err(|_| anyhow::anyhow!("inbox API thread panicked"))??;
+            }
+            // Once no mail can be published any more, the webhook dispatcher finishes
+            drop(events);
+            if let Some(thread) = webhooks_thread {
+                tokio::task::spawn_blocking(move || thread.join())
+                    .await?
+                    .map_err(|_| anyhow::anyhow!("webhook thread panicked"))??;
+            }
+            tracing::info!("edgemail stopped");
+            Ok(())
+        })
+        .await
 }
@@ -0,0 +1,69 @@
+use mail_parser::MessageParser;
+
+/// The searchable parts of a raw message, with MIME encodings undone
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecodedMail {
+    pub subject: String,
+    /// Plain text of the first text part, or of the HTML part if there is no plain one
+    pub text: String,
+    pub has_attachments: bool,
+}
+
+impl DecodedMail {
+    /// Decodes the message, leaving every field empty if it cannot be parsed at all
+    pub fn from_raw(raw: &str) -> Self {
+        let Some(message) = MessageParser::default().parse(raw.as_bytes()) else {
+            return Self::default();
+        };
+        Self {
+            subject: message.subject().unwrap_or_default().to_string(),
+            text: message
+                .body_text(0)
+                .map(|text| text.trim().to_string())
+                .unwrap_or_default(),
+            has_attachments: message.attachment_count() > 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_encoded_subjects_and_bodies() {
+        let decoded = DecodedMail::from_raw(
+            "Subject: =?utf-8?B?WmHFvMOzxYLEhw==?= gęślą\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: quoted-printable\r\n\
+             \r\n\
+             Za=C5=BC=C3=B3=C5=82=C4=87 ja=C5=BA=C5=84\r\n",
+        );
+        assert_eq!(decoded.subject, "Zażółć gęślą");
+        assert_eq!(decoded.text, "Zażółć jaźń");
+        assert!(!decoded.has_attachments);
+    }
+
+    #[test]
+    fn finds_text_and_attachments_in_multipart_mail() {
+        let decoded = DecodedMail::from_raw(
+            "Subject: Invoice\r\n\
+             Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+             \r\n\
+             --b1\r\n\
+             Content-Type: text/html\r\n\
+             \r\n\
+             <p>Your <b>invoice</b> is attached</p>\r\n\
+             --b1\r\n\
+             Content-Type: application/pdf; name=\"invoice.pdf\"\r\n\
+             Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             JVBERi0xLjQK\r\n\
+             --b1--\r\n",
+        );
+        assert_eq!(decoded.subject, "Invoice");
+        assert_eq!(decoded.text, "Your invoice is attached");
+        assert!(decoded.has_attachments);
+    }
+}
@@ -0,0 +1,34 @@
+use crate::api;
+use std::sync::OnceLock;
+use utoipa::OpenApi;
+
+/// OpenAPI description of the inbox API, built from the handlers in `api` and the types they return
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "edgemail inbox API",
+        description = "Read mail delivered to temporary inboxes. Any address at the server's domain works without registration."
+    ),
+    paths(
+        api::list_inbox,
+        api::delete_inbox,
+        api::wait_for_mail,
+        api::stream_inbox,
+        api::claim_inbox,
+        api::set_inbox_ttl,
+        api::get_inbox_message,
+        api::update_inbox_message,
+        api::delete_inbox_message,
+    )
+)]
+struct ApiDoc;
+
+/// Returns the OpenAPI document served at `/openapi.json`, generated once on first use
+pub fn document() -> &'static str {
+    static DOCUMENT: OnceLock<String> = OnceLock::new();
+    DOCUMENT.get_or_init(|| {
+        ApiDoc::openapi()
+            .to_json()
+            .expect("the OpenAPI document is serializable")
+    })
+}
@@ -0,0 +1,87 @@
+/// A pattern matching inbox addresses, e.g. `*@idont.date` or `github_*@idont.date`.
+/// `*` matches any sequence of characters and `?` matches a single character.
+/// Matching is case-insensitive and ignores angle brackets around the address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InboxPattern {
+    pattern: String,
+}
+
+impl InboxPattern {
+    pub fn new(pattern: impl AsRef<str>) -> Self {
+        Self {
+            pattern: normalize_address(pattern.as_ref()),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Checks whether a single address matches the pattern
+    pub fn matches(&self, address: &str) -> bool {
+        let address = normalize_address(address);
+        glob_match(self.pattern.as_bytes(), address.as_bytes())
+    }
+
+    /// Checks whether any address in a comma-separated recipient list matches the pattern
+    pub fn matches_any(&self, recipients: &str) -> bool {
+        recipients.split(',').any(|address| self.matches(address))
+    }
+}
+
+fn normalize_address(address: &str) -> String {
+    address
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .to_lowercase()
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it was tried at
+    let mut backtrack = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, tried)) => {
+                    p = star + 1;
+                    t = tried + 1;
+                    backtrack = Some((star, tried + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_wildcards() {
+        let pattern = InboxPattern::new("github_*@idont.date");
+        assert!(pattern.matches("<github_reset_2@idont.date>"));
+        assert!(pattern.matches("GitHub_@idont.date"));
+        assert!(!pattern.matches("gitlab_reset@idont.date"));
+        assert!(InboxPattern::new("qa?@*").matches("qa1@example.com"));
+        assert!(!InboxPattern::new("qa?@*").matches("qa12@example.com"));
+    }
+
+    #[test]
+    fn matches_any_recipient() {
+        let pattern = InboxPattern::new("*@idont.date");
+        assert!(pattern.matches_any("<a@example.com>, <b@idont.date>"));
+        assert!(!pattern.matches_any("<a@example.com>"));
+    }
+}
@@ -0,0 +1,160 @@
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::str::FromStr;
+use tokio::time::{Duration, Instant};
+
+/// Once this many clients are tracked, buckets which have fully refilled are dropped
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// An allowed request rate: up to `burst` requests at once,
+/// with tokens refilled evenly over `period`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    pub const fn per_minute(burst: u32) -> Self {
+        Self {
+            burst,
+            period: Duration::from_secs(60),
+        }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.burst) / self.period.as_secs_f64()
+    }
+}
+
+impl FromStr for Quota {
+    type Err = anyhow::Error;
+
+    /// Parses quotas like `60/min`, `10/s` or `1000/hour`
+    fn from_str(value: &str) -> Result<Self> {
+        let (burst, unit) = value
+            .split_once('/')
+            .context("rate limit must look like <requests>/<s|min|hour>")?;
+        let burst: u32 = burst
+            .trim()
+            .parse()
+            .context("rate limit must start with a number of requests")?;
+        anyhow::ensure!(burst > 0, "rate limit must allow at least one request");
+        let period = match unit.trim() {
+            "s" | "sec" | "second" => Duration::from_secs(1),
+            "m" | "min" | "minute" => Duration::from_secs(60),
+            "h" | "hour" => Duration::from_secs(3600),
+            unit => anyhow::bail!("unknown rate limit period: {unit}"),
+        };
+        Ok(Self { burst, period })
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket rate limiter, keeping a separate bucket for every key
+#[derive(Debug)]
+pub struct RateLimiter<K> {
+    quota: Quota,
+    buckets: HashMap<K, Bucket>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(quota: Quota) -> Self {
+        Self {
+            quota,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`.
+    /// If the bucket is empty, returns how long to wait until a token is available.
+    pub fn check(&mut self, key: K, now: Instant) -> Result<(), Duration> {
+        if self.buckets.len() >= PRUNE_THRESHOLD {
+            self.prune(now);
+        }
+        let quota = self.quota;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: f64::from(quota.burst),
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens =
+            (bucket.tokens + elapsed * quota.refill_per_sec()).min(f64::from(quota.burst));
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / quota.refill_per_sec(),
+            ))
+        }
+    }
+
+    /// Changes the quota while keeping the tokens left in every bucket,
+    /// up to the new burst size
+    pub fn set_quota(&mut self, quota: Quota) {
+        self.quota = quota;
+    }
+
+    /// Forgets all buckets, which lets every client start over with a full one
+    pub fn reset(&mut self) {
+        self.buckets.clear();
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let quota = self.quota;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * quota.refill_per_sec() < f64::from(quota.burst)
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_quotas() {
+        assert_eq!("60/min".parse::<Quota>().unwrap(), Quota::per_minute(60));
+        assert_eq!(
+            "5/s".parse::<Quota>().unwrap(),
+            Quota {
+                burst: 5,
+                period: Duration::from_secs(1)
+            }
+        );
+        assert!("0/min".parse::<Quota>().is_err());
+        assert!("10/fortnight".parse::<Quota>().is_err());
+        assert!("lots".parse::<Quota>().is_err());
+    }
+
+    #[test]
+    fn limits_bursts_and_refills() {
+        let mut limiter = RateLimiter::new(Quota::per_minute(2));
+        let start = Instant::now();
+        assert!(limiter.check("a", start).is_ok());
+        assert!(limiter.check("a", start).is_ok());
+        let retry_after = limiter.check("a", start).unwrap_err();
+        assert_eq!(retry_after.as_secs(), 30);
+        // Other keys have their own buckets
+        assert!(limiter.check("b", start).is_ok());
+        // Half a minute refills a single token
+        assert!(limiter.check("a", start + Duration::from_secs(30)).is_ok());
+        assert!(limiter.check("a", start + Duration::from_secs(30)).is_err());
+        limiter.reset();
+        assert!(limiter.check("a", start + Duration::from_secs(30)).is_ok());
+
+        // A new quota applies to buckets which are already tracked
+        limiter.set_quota(Quota::per_minute(60));
+        assert!(limiter.check("a", start + Duration::from_secs(31)).is_ok());
+        assert!(limiter.check("a", start + Duration::from_secs(31)).is_ok());
+    }
+}
@@ -0,0 +1,597 @@
+use crate::database::Client;
+use crate::pattern::InboxPattern;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 3600);
+pub const DEFAULT_CLEANUP_INTERVAL: Duration = Duration::from_secs(3600);
+pub const DEFAULT_TRASH_PERIOD: Duration = Duration::from_secs(7 * 24 * 3600);
+/// Messages looked up and deleted at once
+const BATCH_SIZE: u32 = 500;
+/// Names under which the retention job records its progress in the database
+const AGE_LIMITS: &str = "age_limits";
+const COUNT_LIMITS: &str = "count_limits";
+const COUNTED_UNTIL: &str = "counted_until";
+/// Inboxes named like `ttl1h.signup@idont.date` choose their own lifetime
+const TTL_ADDRESS_PREFIX: &str = "ttl";
+
+/// How long mail is kept, and how much of it.
+/// Limits for an inbox come from the first rule whose pattern matches it, or else from the
+/// global limit. A message addressed to several inboxes follows the strictest of their limits.
+/// Inboxes may also have a lifetime of their own, which replaces the maximum age.
+/// Mail removed by the age and count limits goes to the trash, from which it can be restored
+/// until it is purged after `trash_period`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    /// Age after which mail is moved to the trash
+    pub max_age: Duration,
+    pub max_age_rules: Vec<(InboxPattern, Duration)>,
+    /// Number of messages kept per inbox, newest first
+    pub max_messages: Option<u64>,
+    pub max_messages_rules: Vec<(InboxPattern, u64)>,
+    /// Total size of stored messages in bytes, beyond which mail is purged,
+    /// starting with the trash and then the oldest mail. Unlike the other limits,
+    /// it skips the trash, since trashed mail takes up as much space.
+    pub max_storage: Option<u64>,
+    /// How long mail stays in the trash before it is purged
+    pub trash_period: Duration,
+    /// How often the policy is enforced
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            max_age: DEFAULT_MAX_AGE,
+            max_age_rules: Vec::new(),
+            max_messages: None,
+            max_messages_rules: Vec::new(),
+            max_storage: None,
+            trash_period: DEFAULT_TRASH_PERIOD,
+            interval: DEFAULT_CLEANUP_INTERVAL,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    pub fn max_age_for(&self, inbox: &str) -> Duration {
+        self.max_age_rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(inbox))
+            .map_or(self.max_age, |(_, age)| *age)
+    }
+
+    pub fn max_messages_for(&self, inbox: &str) -> Option<u64> {
+        self.max_messages_rules
+            .iter()
+            .find(|(pattern, _)| pattern.matches(inbox))
+            .map(|(_, count)| *count)
+            .or(self.max_messages)
+    }
+
+    /// Age after which a message addressed to these recipients is deleted,
+    /// given the lifetimes which inboxes were given through the API
+    fn max_age_of(&self, recipients: &str, ttls: &HashMap<String, Duration>) -> Duration {
+        recipients
+            .split(", ")
+            .map(|inbox| {
+                let inbox = inbox.trim().trim_matches(['<', '>']).to_lowercase();
+                ttls.get(&inbox)
+                    .copied()
+                    .or_else(|| address_ttl(&inbox))
+                    .unwrap_or_else(|| self.max_age_for(&inbox))
+            })
+            .min()
+            .unwrap_or(self.max_age)
+    }
+
+    /// The parts of the policy which decide the expiry time of mail, in a form
+    /// that tells whether mail was given its expiry time under different limits
+    fn age_limits(&self) -> String {
+        format!("{:?} {:?}", self.max_age, self.max_age_rules)
+    }
+
+    fn count_limits(&self) -> String {
+        format!("{:?} {:?}", self.max_messages, self.max_messages_rules)
+    }
+}
+
+/// Number of messages deleted by each part of the policy, and how long it took
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// Messages purged after their time in the trash
+    pub purged: u64,
+    pub expired: u64,
+    pub over_count: u64,
+    pub over_storage: u64,
+    /// Transactions in which mail was deleted
+    pub batches: u64,
+    pub elapsed: Duration,
+}
+
+impl RetentionReport {
+    /// Messages removed from inboxes, whether moved to the trash or purged
+    pub fn deleted(&self) -> u64 {
+        self.expired + self.over_count + self.over_storage
+    }
+
+    /// Moves one batch of mail to the trash and returns the number of trashed messages
+    async fn trash(&mut self, db: &Client, ids: &[i64]) -> Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        self.batches += 1;
+        db.trash_mail_by_ids(ids).await
+    }
+
+    /// Purges one batch of mail and returns the number of purged messages
+    async fn purge(&mut self, db: &Client, ids: &[i64]) -> Result<u64> {
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        self.batches += 1;
+        db.purge_mail_by_ids(ids).await
+    }
+}
+
+/// Purges mail which has been in the trash for long enough, then moves mail which is too old
+/// and mail beyond the count limits of inboxes to the trash. Finally it purges the trash and
+/// then the oldest mail until the stored messages fit into the storage limit.
+/// Mail is looked up and deleted in batches of at most `BATCH_SIZE` messages,
+/// so that no single transaction holds the database for long.
+pub async fn enforce(db: &Client, policy: &RetentionPolicy) -> Result<RetentionReport> {
+    enforce_in_batches(db, policy, BATCH_SIZE).await
+}
+
+async fn enforce_in_batches(
+    db: &Client,
+    policy: &RetentionPolicy,
+    batch_size: u32,
+) -> Result<RetentionReport> {
+    let started = Instant::now();
+    let mut report = RetentionReport::default();
+    let now = chrono::Utc::now();
+
+    let trash_cutoff = now - chrono::Duration::from_std(policy.trash_period)?;
+    loop {
+        let trashed = db.query_trash_before(trash_cutoff, batch_size).await?;
+        match report.purge(db, &trashed).await? {
+            0 => break,
+            purged => report.purged += purged,
+        }
+    }
+
+    // Every message is given its expiry time once, so that later runs only look at new mail.
+    // Changing the age limits has all of it worked out again.
+    let age_limits = policy.age_limits();
+    if db.retention_state(AGE_LIMITS).await?.as_ref() != Some(&age_limits) {
+        while db.clear_mail_expiry(batch_size).await? > 0 {}
+        db.set_retention_state(AGE_LIMITS, &age_limits).await?;
+    }
+    let ttls: HashMap<String, Duration> = db.list_inbox_ttls().await?.into_iter().collect();
+    loop {
+        let unknown = db.query_mail_without_expiry(batch_size).await?;
+        if unknown.is_empty() {
+            break;
+        }
+        let expiry = unknown
+            .iter()
+            .map(|mail| {
+                let max_age =
+                    chrono::Duration::from_std(policy.max_age_of(&mail.recipients, &ttls))?;
+                Ok((mail.id, mail.date + max_age))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        db.set_mail_expiry(&expiry).await?;
+    }
+    loop {
+        let expired = db.query_expired_mail(now, batch_size).await?;
+        match report.trash(db, &expired).await? {
+            0 => break,
+            trashed => report.expired += trashed,
+        }
+    }
+
+    // Only inboxes which received mail since the last run can have gone over their limits,
+    // unless the limits changed
+    if policy.max_messages.is_some() || !policy.max_messages_rules.is_empty() {
+        let count_limits = policy.count_limits();
+        let counted = if db.retention_state(COUNT_LIMITS).await?.as_ref() == Some(&count_limits) {
+            db.retention_state(COUNTED_UNTIL)
+                .await?
+                .and_then(|id| id.parse().ok())
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        let latest = db.latest_mail_id().await?;
+        let mut after = String::new();
+        loop {
+            let inboxes = db
+                .query_inboxes_of_mail(counted + 1..=latest, &after, batch_size)
+                .await?;
+            let Some(last) = inboxes.last() else {
+                break;
+            };
+            after = last.clone();
+            for inbox in &inboxes {
+                let Some(keep) = policy.max_messages_for(inbox) else {
+                    continue;
+                };
+                loop {
+                    let over_count = db.query_mail_beyond_count(inbox, keep, batch_size).await?;
+                    // Stop once a batch trashes nothing, should mail arrive faster than it is trashed
+                    match report.trash(db, &over_count).await? {
+                        0 => break,
+                        trashed => report.over_count += trashed,
+                    }
+                }
+            }
+        }
+        db.set_retention_state(COUNT_LIMITS, &count_limits).await?;
+        db.set_retention_state(COUNTED_UNTIL, &latest.to_string())
+            .await?;
+    }
+
+    if let Some(max_storage) = policy.max_storage {
+        // The size is summed up once and then kept track of, since summing it is a full scan
+        let mut excess = db
+            .storage_stats()
+            .await?
+            .mail_bytes
+            .saturating_sub(max_storage);
+        while excess > 0 {
+            let mut oldest = Vec::new();
+            let mut freed = 0;
+            for (id, size) in db.query_oldest_mail(batch_size).await? {
+                if freed >= excess {
+                    break;
+                }
+                oldest.push(id);
+                freed += size;
+            }
+            // Trashed mail still takes up space, so it is purged right away
+            match report.purge(db, &oldest).await? {
+                0 => break,
+                purged => report.over_storage += purged,
+            }
+            excess = excess.saturating_sub(freed);
+        }
+    }
+
+    report.elapsed = started.elapsed();
+    if report.deleted() + report.purged > 0 {
+        tracing::info!(
+            "Retention purged {} messages from the trash, deleted {} expired messages, {} over inbox limits and {} over the storage limit in {} batches, taking {:?}",
+            report.purged,
+            report.expired,
+            report.over_count,
+            report.over_storage,
+            report.batches,
+            report.elapsed
+        );
+    } else {
+        tracing::debug!("Retention found nothing to delete in {:?}", report.elapsed);
+    }
+    Ok(report)
+}
+
+/// Returns the lifetime encoded in an address like `ttl1h.signup@idont.date`
+pub fn address_ttl(inbox: &str) -> Option<Duration> {
+    let local = inbox.trim().trim_start_matches('<').split('@').next()?;
+    let prefix = local.get(..TTL_ADDRESS_PREFIX.len())?;
+    if !prefix.eq_ignore_ascii_case(TTL_ADDRESS_PREFIX) {
+        return None;
+    }
+    let (ttl, _) = local[TTL_ADDRESS_PREFIX.len()..].split_once('.')?;
+    parse_duration(&ttl.to_ascii_lowercase()).ok()
+}
+
+/// Parses durations like `90s`, `30m`, `12h`, `7d` or `2w`
+pub fn parse_duration(value: &str) -> Result<Duration> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .context("duration needs a unit: s, m, h, d or w")?;
+    let (count, unit) = value.split_at(split);
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("invalid duration: {value}"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" | "min" => 60,
+        "h" => 3600,
+        "d" => 24 * 3600,
+        "w" => 7 * 24 * 3600,
+        unit => anyhow::bail!("unknown duration unit: {unit}"),
+    };
+    Ok(Duration::from_secs(count * seconds))
+}
+
+/// Parses sizes like `500MB` or `2G` in binary units, where `K` is 1024 bytes
+pub fn parse_size(value: &str) -> Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (count, unit) = value.split_at(split);
+    let count: u64 = count
+        .parse()
+        .with_context(|| format!("invalid size: {value}"))?;
+    let shift = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 0,
+        "K" | "KI" => 10,
+        "M" | "MI" => 20,
+        "G" | "GI" => 30,
+        "T" | "TI" => 40,
+        _ => anyhow::bail!("unknown size unit: {unit}"),
+    };
+    count
+        .checked_mul(1 << shift)
+        .with_context(|| format!("size is too large: {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        store_test_mail, use_broken_test_database, use_private_test_database, MailFilter, PageStart,
+    };
+
+    #[test]
+    fn parses_durations_and_sizes() {
+        assert_eq!(parse_duration("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(
+            parse_duration("12h").unwrap(),
+            Duration::from_secs(12 * 3600)
+        );
+        assert_eq!(
+            parse_duration("2w").unwrap(),
+            Duration::from_secs(14 * 24 * 3600)
+        );
+        assert!(parse_duration("7").is_err());
+        assert!(parse_duration("7y").is_err());
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("500MB").unwrap(), 500 << 20);
+        assert_eq!(parse_size("2g").unwrap(), 2 << 30);
+        assert!(parse_size("2 bananas").is_err());
+    }
+
+    #[test]
+    fn applies_first_matching_rule() {
+        let day = Duration::from_secs(24 * 3600);
+        let policy = RetentionPolicy {
+            max_age: 3 * day,
+            max_age_rules: vec![
+                (InboxPattern::new("qa_*@idont.date"), 28 * day),
+                (InboxPattern::new("*@idont.date"), day),
+            ],
+            max_messages: Some(50),
+            max_messages_rules: vec![(InboxPattern::new("qa_*@idont.date"), 500)],
+            ..RetentionPolicy::default()
+        };
+
+        assert_eq!(policy.max_age_for("<QA_login@idont.date>"), 28 * day);
+        assert_eq!(policy.max_age_for("agent@idont.date"), day);
+        assert_eq!(policy.max_age_for("agent@example.com"), 3 * day);
+        let no_ttls = HashMap::new();
+        assert_eq!(
+            policy.max_age_of("<qa_login@idont.date>, <agent@idont.date>", &no_ttls),
+            day
+        );
+        assert_eq!(policy.max_messages_for("qa_login@idont.date"), Some(500));
+        assert_eq!(policy.max_messages_for("agent@idont.date"), Some(50));
+    }
+
+    #[test]
+    fn prefers_inbox_lifetimes() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(address_ttl("<ttl1h.signup@idont.date>"), Some(hour));
+        assert_eq!(address_ttl("TTL2W.qa@idont.date"), Some(336 * hour));
+        assert_eq!(address_ttl("ttl.signup@idont.date"), None);
+        assert_eq!(address_ttl("ttl1y.signup@idont.date"), None);
+        assert_eq!(address_ttl("t@idont.date"), None);
+        assert_eq!(address_ttl("agent@idont.date"), None);
+
+        let policy = RetentionPolicy {
+            max_age_rules: vec![(InboxPattern::new("*@idont.date"), 24 * hour)],
+            ..RetentionPolicy::default()
+        };
+        let ttls = HashMap::from([("qa@idont.date".to_string(), 336 * hour)]);
+        assert_eq!(policy.max_age_of("<ttl1h.x@idont.date>", &ttls), hour);
+        assert_eq!(policy.max_age_of("<QA@idont.date>", &ttls), 336 * hour);
+        assert_eq!(policy.max_age_of("<agent@idont.date>", &ttls), 24 * hour);
+    }
+
+    #[tokio::test]
+    async fn enforces_age_and_count_limits() {
+        let db = use_private_test_database().await;
+        for (to, subject) in [
+            ("<retention_expired@idont.date>", "Old"),
+            ("<ttl0s.retention@idont.date>", "Gone"),
+            ("<retention_counted@idont.date>", "First"),
+            ("<retention_counted@idont.date>", "Second"),
+            ("<retention_counted@idont.date>", "Third"),
+        ] {
+            store_test_mail(&db, to, &format!("Subject: {subject}\r\n\r\nBody")).await;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let policy = RetentionPolicy {
+            max_age_rules: vec![(
+                InboxPattern::new("retention_expired@idont.date"),
+                Duration::ZERO,
+            )],
+            max_messages_rules: vec![(InboxPattern::new("retention_counted@idont.date"), 2)],
+            ..RetentionPolicy::default()
+        };
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!(report.expired, 2);
+        assert_eq!(report.over_count, 1);
+        assert_eq!(report.batches, 2);
+
+        let list = |inbox: &'static str| {
+            let db = &db;
+            async move {
+                db.query_mail_by_recipient(inbox, &MailFilter::default(), &PageStart::default(), 10)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert!(list("retention_expired@idont.date").await.is_empty());
+        assert!(list("ttl0s.retention@idont.date").await.is_empty());
+        let counted = list("retention_counted@idont.date").await;
+        assert_eq!(counted.len(), 2);
+        assert!(counted.iter().all(|mail| !mail.data.contains("First")));
+
+        // Trashed mail can be restored until the trash period is over
+        assert_eq!(
+            db.restore_mail_by_recipient("retention_expired@idont.date")
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(list("retention_expired@idont.date").await.len(), 1);
+    }
+
+    async fn receive(db: &Client, to: &str, count: usize) -> Vec<i64> {
+        let mut ids = Vec::new();
+        for _ in 0..count {
+            let record = store_test_mail(db, to, "Subject: Hello\r\n\r\nBody").await;
+            ids.push(record.id);
+        }
+        ids
+    }
+
+    #[tokio::test]
+    async fn deletes_in_batches_and_only_looks_at_new_mail() {
+        let db = use_private_test_database().await;
+        receive(&db, "<batched@idont.date>", 5).await;
+        receive(&db, "<counted@idont.date>", 3).await;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let mut policy = RetentionPolicy {
+            max_messages: Some(1),
+            ..RetentionPolicy::default()
+        };
+        let report = enforce_in_batches(&db, &policy, 2).await.unwrap();
+        assert_eq!(report.expired, 0);
+        // Four messages of the first inbox in two batches and two of the second in one
+        assert_eq!(report.over_count, 6);
+        assert_eq!(report.batches, 3);
+
+        // Inboxes without new mail are not counted again, and mail keeps its expiry time
+        let report = enforce_in_batches(&db, &policy, 2).await.unwrap();
+        assert_eq!(report.deleted(), 0);
+        assert_eq!(
+            db.retention_state(COUNTED_UNTIL).await.unwrap().as_deref(),
+            Some("8")
+        );
+
+        // New limits apply to all of the mail
+        policy.max_age_rules = vec![(InboxPattern::new("*@idont.date"), Duration::ZERO)];
+        let report = enforce_in_batches(&db, &policy, 2).await.unwrap();
+        assert_eq!(report.expired, 2);
+        assert_eq!(report.batches, 1);
+        assert_eq!(db.storage_stats().await.unwrap().trashed, 8);
+    }
+
+    #[tokio::test]
+    async fn purges_trash_and_oldest_mail_over_storage_limit() {
+        let db = use_private_test_database().await;
+        let ids = receive(&db, "<stored@idont.date>", 4).await;
+        db.trash_mail_by_ids(&ids[3..]).await.unwrap();
+        let stats = db.storage_stats().await.unwrap();
+        let size = stats.mail_bytes / 4;
+
+        // The newest message goes first, since it is in the trash
+        let policy = RetentionPolicy {
+            max_storage: Some(3 * size),
+            ..RetentionPolicy::default()
+        };
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!(report.over_storage, 1);
+        assert_eq!(report.deleted(), 1);
+        assert_eq!(db.storage_stats().await.unwrap().trashed, 0);
+
+        let policy = RetentionPolicy {
+            max_storage: Some(size + 1),
+            ..RetentionPolicy::default()
+        };
+        let report = enforce_in_batches(&db, &policy, 1).await.unwrap();
+        assert_eq!(report.over_storage, 2);
+        assert_eq!(report.batches, 2);
+        let left = db
+            .query_mail_by_recipient(
+                "stored@idont.date",
+                &MailFilter::default(),
+                &PageStart::default(),
+                10,
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            left.iter().map(|mail| mail.id).collect::<Vec<_>>(),
+            [ids[2]]
+        );
+        // Mail over the storage limit skips the trash, since trashing it would free no space
+        assert_eq!(
+            db.restore_mail_by_recipient("stored@idont.date")
+                .await
+                .unwrap(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn restores_trash_until_it_is_purged() {
+        let db = use_private_test_database().await;
+        receive(&db, "<trashed@idont.date>", 3).await;
+        let lifetime = Duration::from_millis(300);
+        let mut policy = RetentionPolicy {
+            max_age_rules: vec![(InboxPattern::new("trashed@idont.date"), lifetime)],
+            ..RetentionPolicy::default()
+        };
+        tokio::time::sleep(lifetime).await;
+
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!((report.expired, report.purged), (3, 0));
+        assert_eq!(db.storage_stats().await.unwrap().trashed, 3);
+        assert_eq!(
+            db.restore_mail_by_recipient("trashed@idont.date")
+                .await
+                .unwrap(),
+            3
+        );
+        assert_eq!(db.storage_stats().await.unwrap().trashed, 0);
+
+        // Restored mail starts its lifetime over, instead of going straight back to the trash
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!(report.deleted(), 0);
+        assert_eq!(db.storage_stats().await.unwrap().trashed, 0);
+        tokio::time::sleep(lifetime).await;
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!((report.expired, report.purged), (3, 0));
+
+        // Mail is purged once it has been in the trash for the trash period
+        policy.trash_period = Duration::ZERO;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let report = enforce(&db, &policy).await.unwrap();
+        assert_eq!((report.expired, report.purged), (0, 3));
+        assert_eq!(
+            db.restore_mail_by_recipient("trashed@idont.date")
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(db.storage_stats().await.unwrap().messages, 0);
+    }
+
+    #[tokio::test]
+    async fn reports_database_errors() {
+        let db = use_broken_test_database();
+        let err = enforce(&db, &RetentionPolicy::default()).await.unwrap_err();
+        assert!(err.to_string().contains("no such table"), "{err:#}");
+    }
+}
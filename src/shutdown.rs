@@ -0,0 +1,81 @@
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+/// Tells running services that the server is shutting down.
+/// Once shutdown starts, services stop taking new work
+/// and get until the deadline to finish the work they have.
+#[derive(Debug)]
+pub struct Shutdown {
+    sender: watch::Sender<Option<Instant>>,
+}
+
+/// The receiving end of a `Shutdown`, held by every service
+#[derive(Clone, Debug)]
+pub struct ShutdownSignal {
+    receiver: watch::Receiver<Option<Instant>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(None);
+        Self { sender }
+    }
+
+    /// Starts shutting down, giving services until `deadline` to finish
+    pub fn start(&self, deadline: Instant) {
+        self.sender.send_replace(Some(deadline));
+    }
+
+    pub fn subscribe(&self) -> ShutdownSignal {
+        ShutdownSignal {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownSignal {
+    /// Returns the deadline if shutdown has started
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.receiver.borrow()
+    }
+
+    /// Waits until shutdown starts and returns its deadline.
+    /// Never finishes if the `Shutdown` is dropped without starting.
+    pub async fn started(&mut self) -> Instant {
+        match self.receiver.wait_for(Option::is_some).await {
+            Ok(deadline) => deadline.expect("shutdown has started"),
+            Err(_) => std::future::pending().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn signals_deadline() {
+        let shutdown = Shutdown::new();
+        let mut signal = shutdown.subscribe();
+        assert_eq!(signal.deadline(), None);
+        let waiting = tokio::time::timeout(Duration::from_millis(10), signal.started()).await;
+        assert!(waiting.is_err());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        shutdown.start(deadline);
+        assert_eq!(signal.started().await, deadline);
+        // Signals subscribed afterwards see it too
+        assert_eq!(shutdown.subscribe().deadline(), Some(deadline));
+
+        let mut orphan = Shutdown::new().subscribe();
+        let waiting = tokio::time::timeout(Duration::from_millis(10), orphan.started()).await;
+        assert!(waiting.is_err());
+    }
+}
@@ -1,9 +1,49 @@
 use crate::database;
+use crate::events::MailEvents;
+use crate::pattern::InboxPattern;
+use crate::shutdown::ShutdownSignal;
 use anyhow::{Context, Result};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 
+/// Time after which a session is closed, whatever state it is in
+pub const DEFAULT_SESSION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+/// Largest message accepted, in bytes, advertised through the SIZE extension
+pub const DEFAULT_MAX_MESSAGE_SIZE: u64 = 10 * 1024 * 1024;
+/// Admin, administrator, postmaster and hostmaster are refused
+/// to prevent being able to register certificates for the domain.
+/// The check is over-eager, but it also makes it simpler.
+pub const DEFAULT_BLOCKED_RECIPIENTS: &[&str] = &["*admin*", "*postmaster*", "*hostmaster*"];
+
+/// Settings of SMTP sessions. Changes apply to sessions started afterwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub domain: String,
+    pub session_timeout: std::time::Duration,
+    /// Recipients whose mail is not accepted
+    pub blocked_recipients: Vec<InboxPattern>,
+    /// Largest message accepted, in bytes
+    pub max_message_size: u64,
+}
+
+impl Config {
+    pub fn new(domain: impl Into<String>) -> Self {
+        Self {
+            domain: domain.into(),
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+            blocked_recipients: DEFAULT_BLOCKED_RECIPIENTS
+                .iter()
+                .map(InboxPattern::new)
+                .collect(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+/// Marks the end of the data of a message
+const END_OF_DATA: &str = "\r\n.\r\n";
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Mail {
     pub from: String,
@@ -17,12 +57,17 @@ enum State {
     Greeted,
     ReceivingRcpt(Mail),
     ReceivingData(Mail),
+    /// Skipping the rest of a message which is too large, keeping the last bytes read
+    /// to find the end of its data
+    DiscardingData(Vec<u8>),
     Received(Mail),
 }
 
 struct StateMachine {
     state: State,
     ehlo_greeting: String,
+    blocked_recipients: Vec<InboxPattern>,
+    max_message_size: u64,
 }
 
 /// An state machine capable of handling SMTP commands
@@ -36,14 +81,25 @@ impl StateMachine {
     const AUTH_OK: &'static [u8] = b"235 Ok\n";
     const SEND_DATA_PLZ: &'static [u8] = b"354 End data with <CR><LF>.<CR><LF>\n";
     const KTHXBYE: &'static [u8] = b"221 Bye\n";
+    const IP_BANNED: &'static [u8] = b"554 5.7.1 Access denied\n";
+    const SENDER_BANNED: &'static [u8] = b"550 5.7.1 Sender domain is banned\n";
+    const GOTTA_GO: &'static [u8] = b"421 4.3.2 Service shutting down\n";
+    const TRY_AGAIN_LATER: &'static [u8] =
+        b"451 4.3.0 Message could not be stored, try again later\n";
+    const TOO_BIG: &'static [u8] = b"552 5.3.4 Message size exceeds fixed maximum message size\n";
+    const BAD_SIZE: &'static [u8] = b"501 5.5.4 Invalid SIZE parameter\n";
     const HOLD_YOUR_HORSES: &'static [u8] = &[];
 
-    pub fn new(domain: impl AsRef<str>) -> Self {
-        let domain = domain.as_ref();
-        let ehlo_greeting = format!("250-{domain} Hello {domain}\n250 AUTH PLAIN LOGIN\n");
+    pub fn new(config: &Config) -> Self {
+        let domain = &config.domain;
+        let max_size = config.max_message_size;
+        let ehlo_greeting =
+            format!("250-{domain} Hello {domain}\n250-SIZE {max_size}\n250 AUTH PLAIN LOGIN\n");
         Self {
             state: State::Fresh,
             ehlo_greeting,
+            blocked_recipients: config.blocked_recipients.clone(),
+            max_message_size: config.max_message_size,
         }
     }
 
@@ -51,28 +107,66 @@ impl StateMachine {
     pub fn handle_smtp(&mut self, raw_msg: &str) -> Result<&[u8]> {
         tracing::trace!("Received {raw_msg} in state {:?}", self.state);
         let mut msg = raw_msg.split_whitespace();
-        let command = msg.next().context("received empty command")?.to_lowercase();
+        // Empty lines are only valid as data, so they fail like unknown commands otherwise
+        let command = msg.next().unwrap_or_default().to_lowercase();
         let state = std::mem::replace(&mut self.state, State::Fresh);
         match (command.as_str(), state) {
-            ("ehlo", State::Fresh) => {
+            // Everything up to the terminating dot is data, even if it looks like a command
+            (_, State::ReceivingData(mut mail)) => {
+                tracing::trace!("Receiving data");
+                mail.data += raw_msg;
+                // The size of a message includes its final line break, but not the dot after it
+                let size = mail.data.len().saturating_sub(".\r\n".len());
+                if size as u64 > self.max_message_size {
+                    tracing::info!("Message from {} exceeds the size limit", mail.from);
+                    let mut tail = mail.data.into_bytes();
+                    tail.drain(..tail.len().saturating_sub(END_OF_DATA.len()));
+                    return Ok(self.discard_data(tail, ""));
+                }
+                // The terminating dot may come in a read of its own.
+                // The reply waits until the server has stored the message.
+                if mail.data.ends_with(END_OF_DATA) {
+                    tracing::trace!(
+                        "Received data: FROM: {} TO:{} DATA:{}",
+                        mail.from,
+                        mail.to.join(", "),
+                        mail.data
+                    );
+                    self.state = State::Received(mail);
+                } else {
+                    self.state = State::ReceivingData(mail);
+                }
+                Ok(StateMachine::HOLD_YOUR_HORSES)
+            }
+            (_, State::DiscardingData(tail)) => Ok(self.discard_data(tail, raw_msg)),
+            // Greeting again starts over, dropping any message in progress
+            ("ehlo", _) => {
                 tracing::trace!("Sending AUTH info");
                 self.state = State::Greeted;
                 Ok(self.ehlo_greeting.as_bytes())
             }
-            ("helo", State::Fresh) => {
+            ("helo", _) => {
                 self.state = State::Greeted;
                 Ok(StateMachine::KK)
             }
-            ("noop", _) | ("help", _) | ("info", _) | ("vrfy", _) | ("expn", _) => {
+            ("noop", state)
+            | ("help", state)
+            | ("info", state)
+            | ("vrfy", state)
+            | ("expn", state) => {
                 tracing::trace!("Got {command}");
+                self.state = state;
                 Ok(StateMachine::KK)
             }
+            // Drops the message in progress, so that the next one can start with MAIL
+            ("rset", State::Fresh) => Ok(StateMachine::KK),
             ("rset", _) => {
-                self.state = State::Fresh;
+                self.state = State::Greeted;
                 Ok(StateMachine::KK)
             }
-            ("auth", _) => {
+            ("auth", state) => {
                 tracing::trace!("Acknowledging AUTH");
+                self.state = state;
                 Ok(StateMachine::AUTH_OK)
             }
             ("mail", State::Greeted) => {
@@ -82,6 +176,22 @@ impl StateMachine {
                     .strip_prefix("FROM:")
                     .context("received incorrect MAIL")?;
                 tracing::debug!("FROM: {from}");
+                // Senders may declare the size of the message up front
+                let declared_size = msg.find_map(|param| {
+                    let (key, value) = param.split_once('=')?;
+                    key.eq_ignore_ascii_case("size").then_some(value)
+                });
+                if let Some(size) = declared_size {
+                    self.state = State::Greeted;
+                    match size.parse::<u64>() {
+                        Ok(size) if size > self.max_message_size => {
+                            tracing::info!("Refusing a message of {size} bytes from {from}");
+                            return Ok(StateMachine::TOO_BIG);
+                        }
+                        Ok(_) => {}
+                        Err(_) => return Ok(StateMachine::BAD_SIZE),
+                    }
+                }
                 self.state = State::ReceivingRcpt(Mail {
                     from: from.to_string(),
                     ..Default::default()
@@ -94,7 +204,7 @@ impl StateMachine {
                 let to = to.strip_prefix("TO:").context("received incorrect RCPT")?;
                 let to = to.to_lowercase();
                 tracing::debug!("TO: {to}");
-                if Self::legal_recipient(&to) {
+                if self.legal_recipient(&to) {
                     mail.to.push(to);
                 } else {
                     tracing::warn!("Illegal recipient: {to}")
@@ -107,31 +217,7 @@ impl StateMachine {
                 self.state = State::ReceivingData(mail);
                 Ok(StateMachine::SEND_DATA_PLZ)
             }
-            ("quit", State::ReceivingData(mail)) => {
-                tracing::trace!(
-                    "Received data: FROM: {} TO:{} DATA:{}",
-                    mail.from,
-                    mail.to.join(", "),
-                    mail.data
-                );
-                self.state = State::Received(mail);
-                Ok(StateMachine::KTHXBYE)
-            }
-            ("quit", _) => {
-                tracing::warn!("Received quit before getting any data");
-                Ok(StateMachine::KTHXBYE)
-            }
-            (_, State::ReceivingData(mut mail)) => {
-                tracing::trace!("Receiving data");
-                let resp = if raw_msg.ends_with("\r\n.\r\n") {
-                    StateMachine::KK
-                } else {
-                    StateMachine::HOLD_YOUR_HORSES
-                };
-                mail.data += raw_msg;
-                self.state = State::ReceivingData(mail);
-                Ok(resp)
-            }
+            ("quit", _) => Ok(StateMachine::KTHXBYE),
             _ => anyhow::bail!(
                 "Unexpected message received in state {:?}: {raw_msg}",
                 self.state
@@ -139,12 +225,45 @@ impl StateMachine {
         }
     }
 
-    /// Filter out admin, administrator, postmaster and hostmaster
-    /// to prevent being able to register certificates for the domain.
-    /// The check is over-eager, but it also makes it simpler.
-    /// Assumes lowercased.
-    fn legal_recipient(to: &str) -> bool {
-        !to.contains("admin") && !to.contains("postmaster") && !to.contains("hostmaster")
+    /// Skips data until the end of a message which is too large, then refuses it.
+    /// `tail` holds the last bytes skipped so far.
+    fn discard_data(&mut self, mut tail: Vec<u8>, raw_msg: &str) -> &'static [u8] {
+        tail.extend_from_slice(raw_msg.as_bytes());
+        if tail.ends_with(END_OF_DATA.as_bytes()) {
+            self.state = State::Greeted;
+            return StateMachine::TOO_BIG;
+        }
+        tail.drain(..tail.len().saturating_sub(END_OF_DATA.len()));
+        self.state = State::DiscardingData(tail);
+        StateMachine::HOLD_YOUR_HORSES
+    }
+
+    /// Whether a message is on its way, from MAIL until the end of its data
+    fn in_transaction(&self) -> bool {
+        matches!(
+            self.state,
+            State::ReceivingRcpt(_) | State::ReceivingData(_) | State::DiscardingData(_)
+        )
+    }
+
+    /// Takes the message whose data has ended, so that the server can store it
+    /// before replying, and gets ready for the next command
+    fn take_received(&mut self) -> Option<Mail> {
+        if !matches!(self.state, State::Received(_)) {
+            return None;
+        }
+        match std::mem::replace(&mut self.state, State::Greeted) {
+            State::Received(mail) => Some(mail),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Filters out blocked recipients, see DEFAULT_BLOCKED_RECIPIENTS
+    fn legal_recipient(&self, to: &str) -> bool {
+        !self
+            .blocked_recipients
+            .iter()
+            .any(|pattern| pattern.matches(to))
     }
 }
 
@@ -152,55 +271,115 @@ impl StateMachine {
 /// and replicates received messages to the database.
 pub struct Server {
     stream: tokio::net::TcpStream,
+    peer: std::net::IpAddr,
     state_machine: StateMachine,
     db: Arc<Mutex<database::Client>>,
+    events: MailEvents,
 }
 
 impl Server {
-    /// Creates a new server from a connected stream
-    pub async fn new(domain: impl AsRef<str>, stream: tokio::net::TcpStream) -> Result<Self> {
+    /// Creates a new server from a connected stream.
+    /// Stored messages are announced through `events`.
+    pub async fn new(
+        config: &Config,
+        stream: tokio::net::TcpStream,
+        events: MailEvents,
+    ) -> Result<Self> {
         Ok(Self {
+            peer: stream.peer_addr()?.ip(),
             stream,
-            state_machine: StateMachine::new(domain),
+            state_machine: StateMachine::new(config),
             db: Arc::new(Mutex::new(database::Client::new().await?)),
+            events,
         })
     }
 
-    /// Runs the server loop, accepting and handling SMTP commands
-    pub async fn serve(mut self) -> Result<()> {
+    /// Runs the server loop, accepting and handling SMTP commands.
+    /// Once shutdown starts, a message on its way may still be finished before the deadline,
+    /// and every other command is answered with 421.
+    pub async fn serve(mut self, mut shutdown: ShutdownSignal) -> Result<()> {
+        if self.db.lock().await.is_ip_banned(self.peer).await? {
+            tracing::info!("Refusing connection from banned address {}", self.peer);
+            self.stream.write_all(StateMachine::IP_BANNED).await?;
+            return Ok(());
+        }
         self.greet().await?;
 
         let mut buf = vec![0; 1024 * 1024];
+        let mut deadline = None;
         loop {
-            let n = self.stream.read(&mut buf).await?;
+            let n = tokio::select! {
+                n = self.stream.read(&mut buf) => n?,
+                started = shutdown.started(), if deadline.is_none() => {
+                    deadline = Some(started);
+                    if self.state_machine.in_transaction() {
+                        tracing::info!("Shutting down once the message from {} is received", self.peer);
+                        continue;
+                    }
+                    self.stream.write_all(StateMachine::GOTTA_GO).await.ok();
+                    break;
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)), if deadline.is_some() => {
+                    tracing::warn!("Dropping the unfinished message from {} on shutdown", self.peer);
+                    self.stream.write_all(StateMachine::GOTTA_GO).await.ok();
+                    break;
+                }
+            };
 
             if n == 0 {
-                tracing::info!("Received EOF");
-                self.state_machine.handle_smtp("quit").ok();
+                if self.state_machine.in_transaction() {
+                    tracing::info!("Received EOF before the end of the message, dropping it");
+                } else {
+                    tracing::info!("Received EOF");
+                }
                 break;
             }
             let msg = std::str::from_utf8(&buf[0..n])?;
+            if self.state_machine.state == State::Greeted {
+                if let Some(domain) = sender_domain(msg) {
+                    if self.db.lock().await.is_domain_banned(&domain).await? {
+                        tracing::info!("Refusing mail from banned domain {domain}");
+                        self.stream.write_all(StateMachine::SENDER_BANNED).await?;
+                        continue;
+                    }
+                }
+            }
             let response = self.state_machine.handle_smtp(msg)?;
+            let bye = response == StateMachine::KTHXBYE;
             if response != StateMachine::HOLD_YOUR_HORSES {
                 self.stream.write_all(response).await?;
             } else {
                 tracing::debug!("Not responding, awaiting more data");
             }
-            if response == StateMachine::KTHXBYE {
+            if let Some(mail) = self.state_machine.take_received() {
+                let reply = self.store(mail).await;
+                self.stream.write_all(reply).await?;
+            }
+            if bye {
+                break;
+            }
+            // Once shutdown starts, sessions only stay open to finish their message
+            if deadline.is_some() && !self.state_machine.in_transaction() {
+                self.stream.write_all(StateMachine::GOTTA_GO).await.ok();
                 break;
             }
         }
-        match self.state_machine.state {
-            State::Received(mail) => {
-                self.db.lock().await.replicate(mail).await?;
+        Ok(())
+    }
+
+    /// Stores a received message and returns the reply to the end of its data:
+    /// 250 once it is stored, or 451 so that the sender tries again later
+    async fn store(&mut self, mail: Mail) -> &'static [u8] {
+        match self.db.lock().await.replicate(mail).await {
+            Ok(record) => {
+                self.events.publish(record);
+                StateMachine::KK
             }
-            State::ReceivingData(mail) => {
-                tracing::info!("Received EOF before receiving QUIT");
-                self.db.lock().await.replicate(mail).await?;
+            Err(e) => {
+                tracing::error!("Failed to store a message from {}: {e:#}", self.peer);
+                StateMachine::TRY_AGAIN_LATER
             }
-            _ => {}
         }
-        Ok(())
     }
 
     /// Sends the initial SMTP greeting
@@ -212,13 +391,26 @@ impl Server {
     }
 }
 
+/// Extracts the lowercased domain of the sender from a `MAIL FROM` command
+fn sender_domain(command: &str) -> Option<String> {
+    let (verb, rest) = command.trim().split_once(char::is_whitespace)?;
+    if !verb.eq_ignore_ascii_case("mail") {
+        return None;
+    }
+    let (_, domain) = rest.split_once('@')?;
+    let domain = domain
+        .split(|c: char| c == '>' || c.is_whitespace())
+        .next()?;
+    (!domain.is_empty()).then(|| domain.to_lowercase())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_regular_flow() {
-        let mut sm = StateMachine::new("dummy");
+        let mut sm = StateMachine::new(&Config::new("dummy"));
         assert_eq!(sm.state, State::Fresh);
         sm.handle_smtp("HELO localhost").unwrap();
         assert_eq!(sm.state, State::Greeted);
@@ -232,13 +424,268 @@ mod tests {
         assert!(matches!(sm.state, State::ReceivingData(_)));
         sm.handle_smtp("DATA hello world2\n").unwrap();
         assert!(matches!(sm.state, State::ReceivingData(_)));
-        sm.handle_smtp("QUIT").unwrap();
+        // Commands are data until the terminating dot
+        sm.handle_smtp("QUIT\r\n").unwrap();
+        assert!(matches!(sm.state, State::ReceivingData(_)));
+        let resp = sm.handle_smtp("\r\n.\r\n").unwrap();
+        assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+        assert!(matches!(sm.state, State::Received(_)));
+        let mail = sm.take_received().unwrap();
+        assert_eq!(mail.data, "DATA hello world2\nQUIT\r\n\r\n.\r\n");
+        assert_eq!(sm.state, State::Greeted);
+        assert_eq!(sm.take_received(), None);
+        assert_eq!(sm.handle_smtp("QUIT").unwrap(), StateMachine::KTHXBYE);
+    }
+
+    #[test]
+    fn test_several_messages() {
+        let mut sm = StateMachine::new(&Config::new("dummy"));
+        sm.handle_smtp("EHLO localhost").unwrap();
+        for n in 1..=2 {
+            sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+            sm.handle_smtp(&format!("RCPT TO:<n{n}@localhost.com>"))
+                .unwrap();
+            // Commands which change nothing keep the message going
+            sm.handle_smtp("NOOP").unwrap();
+            sm.handle_smtp("DATA").unwrap();
+            sm.handle_smtp(&format!("message {n}\r\n.\r\n")).unwrap();
+            let mail = sm.take_received().unwrap();
+            assert_eq!(mail.to, [format!("<n{n}@localhost.com>")]);
+            assert_eq!(mail.data, format!("message {n}\r\n.\r\n"));
+            assert_eq!(sm.state, State::Greeted);
+        }
+
+        // RSET and greeting again drop the message in progress, but not the session
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RSET").unwrap();
+        assert_eq!(sm.state, State::Greeted);
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("HELO localhost").unwrap();
+        assert_eq!(sm.state, State::Greeted);
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        assert!(matches!(sm.state, State::ReceivingRcpt(_)));
+
+        let mut sm = StateMachine::new(&Config::new("dummy"));
+        sm.handle_smtp("RSET").unwrap();
+        assert_eq!(sm.state, State::Fresh);
+    }
+
+    #[test]
+    fn test_message_size() {
+        let mut config = Config::new("dummy");
+        config.max_message_size = 100;
+        let mut sm = StateMachine::new(&config);
+        let greeting = sm.handle_smtp("EHLO localhost").unwrap();
+        assert!(String::from_utf8_lossy(greeting).contains("250-SIZE 100\n"));
+
+        // Declared sizes are checked at MAIL
+        let resp = sm
+            .handle_smtp("MAIL FROM:<local@example.com> SIZE=101")
+            .unwrap();
+        assert_eq!(resp, StateMachine::TOO_BIG);
+        assert_eq!(sm.state, State::Greeted);
+        let resp = sm
+            .handle_smtp("MAIL FROM:<local@example.com> size=lots")
+            .unwrap();
+        assert_eq!(resp, StateMachine::BAD_SIZE);
+        assert_eq!(sm.state, State::Greeted);
+
+        // Undeclared sizes are checked while receiving data, and refused at its end
+        sm.handle_smtp("MAIL FROM:<local@example.com> SIZE=50")
+            .unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        let line = format!("{}\r\n", "x".repeat(60));
+        assert_eq!(
+            sm.handle_smtp(&line).unwrap(),
+            StateMachine::HOLD_YOUR_HORSES
+        );
+        assert_eq!(
+            sm.handle_smtp(&line).unwrap(),
+            StateMachine::HOLD_YOUR_HORSES
+        );
+        assert!(matches!(sm.state, State::DiscardingData(_)));
+        assert!(sm.in_transaction());
+        assert_eq!(
+            sm.handle_smtp(&line).unwrap(),
+            StateMachine::HOLD_YOUR_HORSES
+        );
+        // The end of data may be split across reads
+        assert_eq!(
+            sm.handle_smtp("\r\n.").unwrap(),
+            StateMachine::HOLD_YOUR_HORSES
+        );
+        assert_eq!(sm.handle_smtp("\r\n").unwrap(), StateMachine::TOO_BIG);
+        assert_eq!(sm.state, State::Greeted);
+
+        // A message of exactly the maximum size is accepted
+        sm.handle_smtp("MAIL FROM:<local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        sm.handle_smtp(&format!("{}\r\n.\r\n", "x".repeat(98)))
+            .unwrap();
+        assert_eq!(sm.take_received().unwrap().data.len(), 103);
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut sm = StateMachine::new(&Config::new("dummy"));
+        sm.handle_smtp("HELO localhost").unwrap();
+        assert!(!sm.in_transaction());
+        sm.handle_smtp("MAIL FROM: <local@example.com>").unwrap();
+        assert!(sm.in_transaction());
+        sm.handle_smtp("RCPT TO: <a@localhost.com>").unwrap();
+        sm.handle_smtp("DATA").unwrap();
+        sm.handle_smtp("Subject: hi\r\n").unwrap();
+        assert!(sm.in_transaction());
+        let resp = sm.handle_smtp("\r\nbody\r\n").unwrap();
+        assert_eq!(resp, StateMachine::HOLD_YOUR_HORSES);
+        assert!(sm.in_transaction());
+        // The terminating dot arrives on its own
+        sm.handle_smtp(".\r\n").unwrap();
         assert!(matches!(sm.state, State::Received(_)));
+        assert!(!sm.in_transaction());
+    }
+
+    #[test]
+    fn test_blocked_recipients() {
+        let mut config = Config::new("dummy");
+        let mut sm = StateMachine::new(&config);
+        sm.handle_smtp("HELO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM: <local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<SysAdmin@localhost.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<postmaster@localhost.com>")
+            .unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("unexpected state {:?}", sm.state);
+        };
+        assert_eq!(mail.to, ["<a@localhost.com>"]);
+
+        config.blocked_recipients = vec![InboxPattern::new("*@localhost.com")];
+        let mut sm = StateMachine::new(&config);
+        sm.handle_smtp("HELO localhost").unwrap();
+        sm.handle_smtp("MAIL FROM: <local@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<postmaster@example.com>").unwrap();
+        sm.handle_smtp("RCPT TO:<a@localhost.com>").unwrap();
+        let State::ReceivingRcpt(mail) = &sm.state else {
+            panic!("unexpected state {:?}", sm.state);
+        };
+        assert_eq!(mail.to, ["<postmaster@example.com>"]);
+    }
+
+    #[test]
+    fn test_sender_domain() {
+        assert_eq!(
+            sender_domain("MAIL FROM:<spammer@Spam.Example> SIZE=100\r\n"),
+            Some("spam.example".to_string())
+        );
+        assert_eq!(sender_domain("mail FROM: <a@b.c>"), Some("b.c".to_string()));
+        assert_eq!(sender_domain("MAIL FROM:<>"), None);
+        assert_eq!(sender_domain("RCPT TO:<a@b.c>"), None);
+    }
+
+    /// Sends `command` and returns the reply
+    async fn exchange(client: &mut tokio::net::TcpStream, command: &str) -> String {
+        client.write_all(command.as_bytes()).await.unwrap();
+        let mut buf = [0; 1024];
+        let n = client.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_stores_each_message() {
+        use crate::shutdown::Shutdown;
+        crate::database::use_test_database();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let events = MailEvents::new();
+        let mut stored = events.subscribe();
+        let server = Server::new(&Config::new("dummy"), stream, events)
+            .await
+            .unwrap();
+        let shutdown = Shutdown::new();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let session = tokio::task::spawn_local(server.serve(shutdown.subscribe()));
+                assert!(exchange(&mut client, "").await.starts_with("220"));
+                exchange(&mut client, "HELO localhost\r\n").await;
+                for inbox in ["first", "second"] {
+                    exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+                    let rcpt = format!("RCPT TO:<{inbox}@smtp-test.example>\r\n");
+                    exchange(&mut client, &rcpt).await;
+                    assert!(exchange(&mut client, "DATA\r\n").await.starts_with("354"));
+                    client
+                        .write_all(b"Subject: hi\r\n\r\nhello\r\n")
+                        .await
+                        .unwrap();
+                    // The reply to the end of data means the message is stored
+                    assert!(exchange(&mut client, ".\r\n").await.starts_with("250"));
+                    let record = stored.try_recv().unwrap();
+                    assert_eq!(record.recipients, format!("<{inbox}@smtp-test.example>"));
+                }
+                assert!(exchange(&mut client, "QUIT\r\n").await.starts_with("221"));
+                session.await.unwrap().unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_storage_failure() {
+        use crate::shutdown::Shutdown;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let events = MailEvents::new();
+        let mut stored = events.subscribe();
+        let server = Server {
+            peer: stream.peer_addr().unwrap().ip(),
+            stream,
+            state_machine: StateMachine::new(&Config::new("dummy")),
+            db: Arc::new(Mutex::new(
+                crate::database::use_failing_test_database().await,
+            )),
+            events,
+        };
+        let shutdown = Shutdown::new();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let session = tokio::task::spawn_local(server.serve(shutdown.subscribe()));
+                assert!(exchange(&mut client, "").await.starts_with("220"));
+                exchange(&mut client, "HELO localhost\r\n").await;
+                exchange(&mut client, "MAIL FROM:<a@example.com>\r\n").await;
+                exchange(&mut client, "RCPT TO:<lost@smtp-test.example>\r\n").await;
+                assert!(exchange(&mut client, "DATA\r\n").await.starts_with("354"));
+                let reply = exchange(&mut client, "Subject: hi\r\n\r\nhello\r\n.\r\n").await;
+                assert!(reply.starts_with("451 4.3.0"), "{reply}");
+                assert!(stored.try_recv().is_err());
+
+                // The client may try again right away, in the same session
+                assert!(exchange(&mut client, "MAIL FROM:<a@example.com>\r\n")
+                    .await
+                    .starts_with("250"));
+                assert!(
+                    exchange(&mut client, "RCPT TO:<lost@smtp-test.example>\r\n")
+                        .await
+                        .starts_with("250")
+                );
+                assert!(exchange(&mut client, "QUIT\r\n").await.starts_with("221"));
+                session.await.unwrap().unwrap();
+            })
+            .await;
     }
 
     #[test]
     fn test_no_greeting() {
-        let mut sm = StateMachine::new("dummy");
+        let mut sm = StateMachine::new(&Config::new("dummy"));
         assert_eq!(sm.state, State::Fresh);
         for command in [
             "MAIL FROM: <local@example.com>",
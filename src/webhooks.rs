@@ -0,0 +1,572 @@
+use crate::api::InboxMessageSummary;
+use crate::database::{Client, DeliveryStatus, MailRecord, WebhookDelivery};
+use crate::events::MailEvents;
+use crate::pattern::InboxPattern;
+use crate::shutdown::ShutdownSignal;
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::rc::Rc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio::task::JoinSet;
+use tokio::time::{timeout_at, Duration};
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>`, keyed with the webhook secret
+pub const SIGNATURE_HEADER: &str = "X-Edgemail-Signature";
+/// Header carrying the id of the delivery in the `webhook_deliveries` table
+pub const DELIVERY_HEADER: &str = "X-Edgemail-Delivery";
+
+const MAX_ATTEMPTS: u32 = 5;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A webhook URL notified about accepted mail,
+/// either for every inbox or only for inboxes matching a pattern.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub pattern: Option<InboxPattern>,
+}
+
+impl Webhook {
+    /// Parses a webhook specification in the `[PATTERN=]URL` format
+    pub fn parse(spec: &str) -> Result<Self> {
+        let (pattern, url) = match spec.split_once('=') {
+            Some((pattern, url)) if !pattern.contains("://") => {
+                (Some(InboxPattern::new(pattern)), url)
+            }
+            _ => (None, spec),
+        };
+        anyhow::ensure!(
+            url.starts_with("http://") || url.starts_with("https://"),
+            "webhook URL must start with http:// or https://: {url}"
+        );
+        Ok(Self {
+            url: url.to_string(),
+            pattern,
+        })
+    }
+
+    /// Checks whether the webhook should be notified about the mail
+    pub fn applies_to(&self, record: &MailRecord) -> bool {
+        self.pattern
+            .as_ref()
+            .is_none_or(|pattern| pattern.matches_any(&record.recipients))
+    }
+}
+
+/// Posts JSON summaries of accepted mail to the configured webhooks,
+/// retrying failed deliveries with exponential backoff.
+struct Dispatcher {
+    db: Rc<Client>,
+    http: reqwest::Client,
+    secret: String,
+    initial_backoff: Duration,
+}
+
+impl Dispatcher {
+    /// Delivers the mail to a single webhook and returns the id of the delivery record
+    async fn deliver(&self, url: &str, record: &MailRecord) -> Result<i64> {
+        let delivery_id = self.db.create_webhook_delivery(url, record.id).await?;
+        self.attempt(delivery_id, url, record, 1).await?;
+        Ok(delivery_id)
+    }
+
+    /// Continues a delivery which was still pending or being retried when the server stopped
+    async fn resume(&self, delivery: &WebhookDelivery) -> Result<()> {
+        let Some(record) = self.db.query_mail_by_id(delivery.mail_id).await? else {
+            tracing::warn!(
+                "Giving up on delivering mail {} to webhook {}: the mail is gone",
+                delivery.mail_id,
+                delivery.url
+            );
+            let attempts = u32::try_from(delivery.attempts).unwrap_or(MAX_ATTEMPTS);
+            return self
+                .db
+                .update_webhook_delivery(
+                    delivery.id,
+                    DeliveryStatus::Failed,
+                    attempts,
+                    Some("mail was deleted before it could be delivered"),
+                )
+                .await;
+        };
+        let attempt = u32::try_from(delivery.attempts).unwrap_or(0) + 1;
+        self.attempt(
+            delivery.id,
+            &delivery.url,
+            &record,
+            attempt.min(MAX_ATTEMPTS),
+        )
+        .await
+    }
+
+    /// Posts the mail until it is accepted or `MAX_ATTEMPTS` is reached, starting at `first_attempt`
+    async fn attempt(
+        &self,
+        delivery_id: i64,
+        url: &str,
+        record: &MailRecord,
+        first_attempt: u32,
+    ) -> Result<()> {
+        let body = serde_json::to_string(&InboxMessageSummary::from(record))?;
+        let signature = sign(&self.secret, &body);
+
+        let mut backoff = self.initial_backoff * 2u32.pow(first_attempt - 1);
+        for attempt in first_attempt..=MAX_ATTEMPTS {
+            let result = self
+                .http
+                .post(url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .header(DELIVERY_HEADER, delivery_id)
+                .body(body.clone())
+                .timeout(DELIVERY_TIMEOUT)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status);
+            match result {
+                Ok(_) => {
+                    tracing::debug!("Delivered mail {} to webhook {url}", record.id);
+                    return self
+                        .db
+                        .update_webhook_delivery(
+                            delivery_id,
+                            DeliveryStatus::Delivered,
+                            attempt,
+                            None,
+                        )
+                        .await;
+                }
+                Err(err) => {
+                    let error = err.to_string();
+                    let status = if attempt == MAX_ATTEMPTS {
+                        tracing::warn!(
+                            "Giving up on delivering mail {} to webhook {url}: {error}",
+                            record.id
+                        );
+                        DeliveryStatus::Failed
+                    } else {
+                        tracing::debug!(
+                            "Webhook delivery to {url} failed, retrying in {backoff:?}: {error}"
+                        );
+                        DeliveryStatus::Retrying
+                    };
+                    self.db
+                        .update_webhook_delivery(delivery_id, status, attempt, Some(&error))
+                        .await?;
+                }
+            }
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Computes the value of the signature header for a request body
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Spawns a thread delivering every mail published on `events`
+/// to the matching webhooks, signed with `secret`.
+/// The thread finishes once all senders of `events` are gone, after waiting for the deliveries
+/// in progress until the shutdown deadline.
+pub fn spawn(
+    webhooks: Vec<Webhook>,
+    secret: String,
+    events: &MailEvents,
+    shutdown: ShutdownSignal,
+) -> std::thread::JoinHandle<Result<()>> {
+    // Subscribe right away, so that no mail is missed while the thread starts
+    let receiver = events.subscribe();
+    std::thread::spawn(move || -> Result<()> {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .context("failed to build async runtime")?
+            .block_on(async move {
+                let local = tokio::task::LocalSet::new();
+                local
+                    .run_until(async move {
+                        let dispatched = async {
+                            let db = Rc::new(Client::new().await?);
+                            dispatch(db, webhooks, secret, receiver, shutdown).await
+                        };
+                        if let Err(err) = dispatched.await {
+                            tracing::error!("Webhook dispatcher failed: {}", err);
+                        }
+                    })
+                    .await;
+            });
+        Ok(())
+    })
+}
+
+/// Delivers the mail from `receiver` until it is closed, after resuming the deliveries
+/// which were left unfinished by the previous run.
+async fn dispatch(
+    db: Rc<Client>,
+    webhooks: Vec<Webhook>,
+    secret: String,
+    mut receiver: Receiver<MailRecord>,
+    shutdown: ShutdownSignal,
+) -> Result<()> {
+    let dispatcher = Rc::new(Dispatcher {
+        db,
+        http: reqwest::Client::new(),
+        secret,
+        initial_backoff: INITIAL_BACKOFF,
+    });
+    // Mail up to this id is either delivered already or about to be received
+    let mut last_dispatched = dispatcher.db.latest_mail_id().await?;
+    // Mail up to this id was fetched from the database after lagging behind,
+    // so it is skipped when it is received as well
+    let mut caught_up = 0;
+    let mut deliveries = JoinSet::new();
+    for delivery in dispatcher.db.query_unfinished_webhook_deliveries().await? {
+        let dispatcher = dispatcher.clone();
+        let configured = webhooks.iter().any(|webhook| webhook.url == delivery.url);
+        deliveries.spawn_local(async move {
+            let resumed = if configured {
+                dispatcher.resume(&delivery).await
+            } else {
+                dispatcher
+                    .db
+                    .update_webhook_delivery(
+                        delivery.id,
+                        DeliveryStatus::Failed,
+                        u32::try_from(delivery.attempts).unwrap_or(MAX_ATTEMPTS),
+                        Some("webhook is no longer configured"),
+                    )
+                    .await
+            };
+            if let Err(err) = resumed {
+                tracing::error!(
+                    "Failed to record webhook delivery to {}: {}",
+                    delivery.url,
+                    err
+                );
+            }
+        });
+    }
+    loop {
+        let received = tokio::select! {
+            received = receiver.recv() => received,
+            // Finished deliveries are collected, so that they do not pile up
+            Some(_) = deliveries.join_next() => continue,
+        };
+        let records = match received {
+            Ok(record) if record.id <= caught_up => continue,
+            Ok(record) => vec![record],
+            Err(RecvError::Lagged(skipped)) => {
+                tracing::debug!("Webhook dispatcher lagged behind by {skipped} messages");
+                let missed = dispatcher
+                    .db
+                    .query_all_mail_after_id(last_dispatched)
+                    .await?;
+                caught_up = missed.last().map_or(caught_up, |record| record.id);
+                missed
+            }
+            // No more mail can arrive, since the server is shutting down
+            Err(RecvError::Closed) => break,
+        };
+        for record in records {
+            last_dispatched = last_dispatched.max(record.id);
+            let record = Rc::new(record);
+            for webhook in webhooks
+                .iter()
+                .filter(|webhook| webhook.applies_to(&record))
+            {
+                let dispatcher = dispatcher.clone();
+                let record = record.clone();
+                let url = webhook.url.clone();
+                deliveries.spawn_local(async move {
+                    if let Err(err) = dispatcher.deliver(&url, &record).await {
+                        tracing::error!("Failed to record webhook delivery to {url}: {}", err);
+                    }
+                });
+            }
+        }
+    }
+
+    let Some(deadline) = shutdown.deadline() else {
+        while deliveries.join_next().await.is_some() {}
+        return Ok(());
+    };
+    let drained = timeout_at(deadline, async {
+        while deliveries.join_next().await.is_some() {}
+    });
+    if drained.await.is_err() {
+        tracing::warn!(
+            "Abandoning {} webhook deliveries which did not finish before shutdown",
+            deliveries.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shutdown::Shutdown;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn parses_global_and_pattern_webhooks() {
+        let global = Webhook::parse("https://example.com/hook?token=abc").unwrap();
+        assert_eq!(global.url, "https://example.com/hook?token=abc");
+        assert_eq!(global.pattern, None);
+
+        let scoped = Webhook::parse("github_*@idont.date=http://localhost:9000/hook").unwrap();
+        assert_eq!(scoped.url, "http://localhost:9000/hook");
+        assert_eq!(
+            scoped.pattern,
+            Some(InboxPattern::new("github_*@idont.date"))
+        );
+
+        assert!(Webhook::parse("ftp://example.com").is_err());
+    }
+
+    /// Accepts a single HTTP request, answers it with `status`
+    /// and returns the raw request.
+    async fn serve_once(listener: &TcpListener, status: u16) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some((headers, body)) = text.split_once("\r\n\r\n") {
+                let length = headers
+                    .lines()
+                    .find_map(|line| {
+                        line.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|v| v.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+                if body.len() >= length {
+                    break;
+                }
+            }
+        }
+        let response =
+            format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+        stream.write_all(response.as_bytes()).await.unwrap();
+        String::from_utf8(request).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_and_signs_deliveries() {
+        crate::database::use_test_database();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let dispatcher = Dispatcher {
+            db: Rc::new(Client::new().await.unwrap()),
+            http: reqwest::Client::new(),
+            secret: "s3cret".to_string(),
+            initial_backoff: Duration::from_millis(10),
+        };
+        let record = MailRecord {
+            id: 1234,
+            date: "2026-05-18 10:00:00.000".to_string(),
+            sender: "<noreply@example.com>".to_string(),
+            recipients: "<hook@idont.date>".to_string(),
+            data: "Subject: Hooked\r\n\r\nBody".to_string(),
+            subject: "Hooked".to_string(),
+            seen: false,
+            flagged: false,
+            key: "0f1e".to_string(),
+        };
+
+        let (delivery_id, (failed, succeeded)) =
+            tokio::join!(dispatcher.deliver(&url, &record), async {
+                (
+                    serve_once(&listener, 500).await,
+                    serve_once(&listener, 200).await,
+                )
+            });
+        let delivery_id = delivery_id.unwrap();
+        assert!(failed.starts_with("POST /hook"));
+
+        let (_, body) = succeeded.split_once("\r\n\r\n").unwrap();
+        assert!(body.contains("\"subject\":\"Hooked\""), "{body}");
+        let expected = format!(
+            "{}: {}",
+            SIGNATURE_HEADER.to_lowercase(),
+            sign("s3cret", body)
+        );
+        assert!(
+            succeeded.to_lowercase().contains(&expected.to_lowercase()),
+            "{succeeded}"
+        );
+
+        let delivery = dispatcher
+            .db
+            .query_webhook_delivery(delivery_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(delivery.status, "delivered");
+        assert_eq!(delivery.attempts, 2);
+        assert_eq!(delivery.mail_id, 1234);
+    }
+
+    #[tokio::test]
+    async fn finishes_deliveries_until_the_shutdown_deadline() {
+        let db = Rc::new(crate::database::use_private_test_database().await);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        // Nothing listens there any more, so deliveries to it keep being retried
+        let refused = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let refused = format!("http://{}/hook", refused.local_addr().unwrap());
+        let webhooks = vec![
+            Webhook::parse(&url).unwrap(),
+            Webhook::parse(&refused).unwrap(),
+        ];
+        let events = MailEvents::new();
+        let shutdown = Shutdown::new();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let dispatcher = tokio::task::spawn_local(dispatch(
+                    db,
+                    webhooks,
+                    "s3cret".to_string(),
+                    events.subscribe(),
+                    shutdown.subscribe(),
+                ));
+                events.publish(MailRecord {
+                    id: 4321,
+                    date: "2026-05-18 10:00:00.000".to_string(),
+                    sender: "<noreply@example.com>".to_string(),
+                    recipients: "<late_hook@idont.date>".to_string(),
+                    data: "Subject: Late\r\n\r\nBody".to_string(),
+                    subject: "Late".to_string(),
+                    seen: false,
+                    flagged: false,
+                    key: "0f1e".to_string(),
+                });
+                shutdown.start(tokio::time::Instant::now() + Duration::from_millis(500));
+                drop(events);
+
+                // Mail published before shutdown is still delivered
+                let request = serve_once(&listener, 200).await;
+                assert!(request.contains("\"subject\":\"Late\""), "{request}");
+                tokio::time::timeout(Duration::from_secs(5), dispatcher)
+                    .await
+                    .expect("dispatcher kept retrying past the deadline")
+                    .unwrap()
+                    .unwrap();
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn delivers_mail_missed_while_lagging_behind() {
+        let db = Rc::new(crate::database::use_private_test_database().await);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let webhooks = vec![Webhook::parse(&format!("lagging@idont.date={url}")).unwrap()];
+        let events = MailEvents::new();
+        let shutdown = Shutdown::new();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let dispatcher = tokio::task::spawn_local(dispatch(
+                    db.clone(),
+                    webhooks,
+                    "s3cret".to_string(),
+                    events.subscribe(),
+                    shutdown.subscribe(),
+                ));
+                // Lets the dispatcher note the newest mail before any arrives
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                let record = crate::database::store_test_mail(
+                    &db,
+                    "<lagging@idont.date>",
+                    "Subject: Missed\r\n\r\nBody",
+                )
+                .await;
+                // Overflows the channel, which drops the oldest message
+                events.publish(record.clone());
+                for _ in 0..256 {
+                    events.publish(MailRecord {
+                        recipients: "<elsewhere@idont.date>".to_string(),
+                        ..record.clone()
+                    });
+                }
+
+                let request =
+                    tokio::time::timeout(Duration::from_secs(5), serve_once(&listener, 200))
+                        .await
+                        .expect("the missed mail was never delivered");
+                assert!(request.contains("\"subject\":\"Missed\""), "{request}");
+                drop(events);
+                dispatcher.await.unwrap().unwrap();
+                assert!(db
+                    .query_unfinished_webhook_deliveries()
+                    .await
+                    .unwrap()
+                    .is_empty());
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    async fn resumes_unfinished_deliveries_on_start() {
+        let db = Rc::new(crate::database::use_private_test_database().await);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let record = crate::database::store_test_mail(
+            &db,
+            "<resumed@idont.date>",
+            "Subject: Resumed\r\n\r\nBody",
+        )
+        .await;
+        let resumed = db.create_webhook_delivery(&url, record.id).await.unwrap();
+        db.update_webhook_delivery(resumed, DeliveryStatus::Retrying, 2, Some("refused"))
+            .await
+            .unwrap();
+        let removed = db
+            .create_webhook_delivery("http://127.0.0.1:1/removed", record.id)
+            .await
+            .unwrap();
+        let events = MailEvents::new();
+        let shutdown = Shutdown::new();
+        let local = tokio::task::LocalSet::new();
+        local
+            .run_until(async move {
+                let dispatcher = tokio::task::spawn_local(dispatch(
+                    db.clone(),
+                    vec![Webhook::parse(&url).unwrap()],
+                    "s3cret".to_string(),
+                    events.subscribe(),
+                    shutdown.subscribe(),
+                ));
+                let request = serve_once(&listener, 200).await;
+                assert!(request.contains("\"subject\":\"Resumed\""), "{request}");
+                let header = format!("{}: {resumed}", DELIVERY_HEADER.to_lowercase());
+                assert!(request.to_lowercase().contains(&header), "{request}");
+                drop(events);
+                dispatcher.await.unwrap().unwrap();
+
+                let delivery = db.query_webhook_delivery(resumed).await.unwrap().unwrap();
+                assert_eq!(delivery.status, "delivered");
+                assert_eq!(delivery.attempts, 3);
+                let delivery = db.query_webhook_delivery(removed).await.unwrap().unwrap();
+                assert_eq!(delivery.status, "failed");
+            })
+            .await;
+    }
+}